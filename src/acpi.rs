@@ -0,0 +1,59 @@
+use core::{mem, ptr};
+use memory::mmio::{self, CacheMode, Mmio};
+use memory::paging::PhysicalAddress;
+use multiboot2::BootInformation;
+
+/// The size of the header shared by the system description tables.
+pub const HEADER_SIZE: usize = 36;
+
+/// Finds the system description table with the specified signature.
+///
+/// Walks the XSDT, or the RSDT before ACPI 2.0, and maps the table.
+/// Returns `None` if the firmware provides no such table.
+pub fn find_table(mb2_info: &BootInformation, signature: &[u8; 4]) -> Option<Mmio> {
+    let rsdp = mb2_info.rsdp_addr()?;
+    let (root, entry_size) = unsafe {
+        let revision = *((rsdp + 15) as *const u8);
+        if revision >= 2 {
+            (*((rsdp + 24) as *const u64) as usize, 8)
+        } else {
+            (*((rsdp + 16) as *const u32) as usize, 4)
+        }
+    };
+    let root = map_table(root)?;
+    let mut entry = HEADER_SIZE;
+    while entry + entry_size <= root.len() {
+        let table = if entry_size == 8 {
+            read::<u64>(&root, entry) as usize
+        } else {
+            read::<u32>(&root, entry) as usize
+        };
+        entry += entry_size;
+        let header = mmio::ioremap(table, HEADER_SIZE, CacheMode::WriteBack).ok()?;
+        if read::<[u8; 4]>(&header, 0) == *signature {
+            drop(header);
+            return map_table(table);
+        }
+    }
+    None
+}
+
+/// Maps a whole system description table.
+fn map_table(table: PhysicalAddress) -> Option<Mmio> {
+    let length = {
+        let header = mmio::ioremap(table, HEADER_SIZE, CacheMode::WriteBack).ok()?;
+        read::<u32>(&header, 4) as usize
+    };
+    if length < HEADER_SIZE {
+        return None;
+    }
+    mmio::ioremap(table, length, CacheMode::WriteBack).ok()
+}
+
+/// Reads a value at the specified offset of a mapped table.
+///
+/// Table fields need not be aligned.
+pub fn read<T: Copy>(table: &Mmio, offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= table.len(), "ACPI table read out of bounds");
+    unsafe { ptr::read_unaligned((table.addr() + offset) as *const T) }
+}
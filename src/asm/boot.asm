@@ -1,12 +1,17 @@
 global real_mode_start
+global stack_bottom
 extern kmain_setup
 extern kmain
 
+; The virtual address the kernel is linked at
+KERNEL_OFFSET equ 0xFFFFFFFF80000000
+
 %include "paging.asm"
 %include "gdt.asm"
 
 ; Real mode text section
-section .text
+; (linked at its physical address)
+section .trampoline progbits alloc exec nowrite align=16
 bits 32
 
 ; Real mode entry point
@@ -15,8 +20,8 @@ real_mode_start:
   ; Disable interrupts
   cli
 
-  ; Point stack pointer to stack
-  mov esp, stack_top
+  ; Point stack pointer to the physical address of the stack
+  mov esp, stack_top - KERNEL_OFFSET
 
   ; Load the multiboot info pointer
   mov edi, ebx
@@ -25,9 +30,9 @@ real_mode_start:
   call setup_paging
 
   ; Map the P4 table
-  mov eax, page_map_level4_table
+  mov eax, page_map_level4_table - KERNEL_OFFSET
   or eax, 0b11
-  mov [page_map_level4_table + 511 * 8], eax
+  mov [page_map_level4_table - KERNEL_OFFSET + 510 * 8], eax
 
   ; Load the global descriptor table
   lgdt [gdt64.pointer]
@@ -35,6 +40,17 @@ real_mode_start:
   ; Jump into long mode
   jmp enter_long_mode
 
+; Long mode trampoline section
+section .trampoline
+bits 64
+
+; Long mode trampoline
+long_mode_trampoline:
+
+  ; Jump into the higher half
+  mov rax, long_mode_start
+  jmp rax
+
 ; Long mode text section
 section .text
 bits 64
@@ -42,6 +58,21 @@ bits 64
 ; Long mode entry point
 long_mode_start:
 
+  ; Point stack pointer to the virtual address of the stack
+  mov rsp, stack_top
+
+  ; Reload the global descriptor table at its virtual address
+  lgdt [gdt64.pointer_high]
+
+  ; Adjust the multiboot info pointer to its virtual address
+  mov rax, KERNEL_OFFSET
+  add rdi, rax
+
+  ; Remove the identity mapping
+  mov qword [page_map_level4_table], 0
+  mov rax, cr3
+  mov cr3, rax
+
   ; Call the kernel setup
   call kmain_setup
 
@@ -56,6 +87,7 @@ section .bss
 align 4096
 
 ; Bootstrap stack
+; (the page below is unmapped by the kernel as a guard page)
 stack_bottom:
   resb 4096
 stack_top:
@@ -1,4 +1,4 @@
-section .text
+section .trampoline progbits alloc exec nowrite align=16
 bits 32
 
 ; Set the GDT up
@@ -11,9 +11,11 @@ enter_long_mode:
   mov es, ax
 
   ; Far jump into long mode
-  jmp gdt64.code:long_mode_start
+  jmp gdt64.code:long_mode_trampoline
 
-section .rodata
+; Bootstrap data
+; (linked at its physical address, so that lgdt can load it)
+section .trampoline
 
 ; Defines
 GDT_BIT_READWRITE   equ 41
@@ -22,7 +24,8 @@ GDT_BIT_TYPE        equ 44
 GDT_BIT_PRESENT     equ 47
 GDT_BIT_LONG        equ 53
 
-; Global Descriptor Table
+; Bootstrap Global Descriptor Table
+; (replaced by the kernel in gdt::init)
 gdt64:
 
   ; Zero segment
@@ -49,3 +52,8 @@ gdt64:
   .pointer:
     dw .pointer - gdt64 - 1
     dq gdt64
+
+  ; GDT pointer to the higher half mapping
+  .pointer_high:
+    dw .pointer - gdt64 - 1
+    dq gdt64 + KERNEL_OFFSET
@@ -0,0 +1,78 @@
+global isr_stub_table
+extern interrupt_dispatch
+
+section .text
+bits 64
+
+; Interrupt service routine stubs
+;
+; Every stub pushes a dummy error code (unless the CPU
+; already pushed one) and its vector number, so that
+; all interrupts share the same stack layout.
+%assign i 0
+%rep 256
+isr_stub_%+i:
+  %if (i == 8) || (i >= 10 && i <= 14) || (i == 17) || (i == 21) || (i == 29) || (i == 30)
+  %else
+    push 0
+  %endif
+  push i
+  jmp isr_common
+%assign i i+1
+%endrep
+
+; Common interrupt entry point
+isr_common:
+
+  ; Save the general purpose registers
+  push rax
+  push rbx
+  push rcx
+  push rdx
+  push rsi
+  push rdi
+  push rbp
+  push r8
+  push r9
+  push r10
+  push r11
+  push r12
+  push r13
+  push r14
+  push r15
+
+  ; Pass the interrupt frame to the kernel
+  mov rdi, rsp
+  cld
+  call interrupt_dispatch
+
+  ; Restore the general purpose registers
+  pop r15
+  pop r14
+  pop r13
+  pop r12
+  pop r11
+  pop r10
+  pop r9
+  pop r8
+  pop rbp
+  pop rdi
+  pop rsi
+  pop rdx
+  pop rcx
+  pop rbx
+  pop rax
+
+  ; Drop the vector number and error code
+  add rsp, 16
+  iretq
+
+section .rodata
+
+; Interrupt service routine stub addresses
+isr_stub_table:
+%assign i 0
+%rep 256
+  dq isr_stub_%+i
+%assign i i+1
+%endrep
@@ -1,25 +1,28 @@
-section .text
+section .trampoline progbits alloc exec nowrite align=16
 bits 32
 
 ; Set paging up
+; (maps the first GiB both at 0 and at KERNEL_OFFSET)
 setup_paging:
 
-  ; Point PML4 to PDP
-  mov eax, page_directory_pointer_table
+  ; Point the first and the last PML4 entry to PDP
+  mov eax, page_directory_pointer_table - KERNEL_OFFSET
   or eax, 0b11
-  mov dword [page_map_level4_table], eax
+  mov dword [page_map_level4_table - KERNEL_OFFSET], eax
+  mov dword [page_map_level4_table - KERNEL_OFFSET + 511 * 8], eax
 
-  ; Point PDP to PD
-  mov eax, page_directory_table
+  ; Point the first and the second to last PDP entry to PD
+  mov eax, page_directory_table - KERNEL_OFFSET
   or eax, 0b11
-  mov dword [page_directory_pointer_table], eax
+  mov dword [page_directory_pointer_table - KERNEL_OFFSET], eax
+  mov dword [page_directory_pointer_table - KERNEL_OFFSET + 510 * 8], eax
 
   ; Initialize counter
   mov ecx, 0
   call .map_page_directory_table
 
   ; Move page table to cr3
-  mov eax, page_map_level4_table
+  mov eax, page_map_level4_table - KERNEL_OFFSET
   mov cr3, eax
 
   ; Enable physical address extension
@@ -45,7 +48,7 @@ setup_paging:
     mov eax, 0x200000
     mul ecx
     or eax, 0b10000011
-    mov [page_directory_table + ecx * 8], eax
+    mov [page_directory_table - KERNEL_OFFSET + ecx * 8], eax
     inc ecx
     cmp ecx, 512
     jne .map_page_directory_table
@@ -54,9 +57,20 @@ setup_paging:
 section .bss
 align 4096
 
+; Exported so the kernel can reserve their frames
+global page_map_level4_table
+global page_directory_pointer_table
+global page_directory_table
+
 page_map_level4_table:
   resb 4096
 page_directory_pointer_table:
   resb 4096
 page_directory_table:
   resb 4096
+
+; Maps physical memory beyond the first GiB until the kernel is remapped
+global early_window_table
+
+early_window_table:
+  resb 4096
@@ -0,0 +1,211 @@
+use core::arch::asm;
+use interrupts;
+
+/// The `CpuidResult` type.
+///
+/// Holds the registers returned by `cpuid`.
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Executes `cpuid` for the specified leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // LLVM reserves rbx, so it is saved around cpuid
+        asm!("mov {0:r}, rbx",
+             "cpuid",
+             "xchg {0:r}, rbx",
+             out(reg) ebx,
+             inout("eax") leaf => eax,
+             inout("ecx") subleaf => ecx,
+             out("edx") edx,
+             options(nostack, preserves_flags));
+    }
+    CpuidResult {
+        eax,
+        ebx,
+        ecx,
+        edx,
+    }
+}
+
+/// Reads a model specific register.
+///
+/// # Safety
+///
+/// The register must exist, or the CPU raises a general protection fault.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nostack));
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Writes a model specific register.
+///
+/// # Safety
+///
+/// The register must exist and the value must not break
+/// any assumption the kernel makes about the CPU state.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nostack));
+}
+
+/// Reads a byte from an I/O port.
+///
+/// # Safety
+///
+/// Reading a port can have side effects on the device behind it.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a byte to an I/O port.
+///
+/// # Safety
+///
+/// The write must not put the device behind the port
+/// into a state the kernel doesn't expect.
+pub unsafe fn outb(value: u8, port: u16) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// The extended feature enable MSR.
+const IA32_EFER: u32 = 0xC0000080;
+
+/// The no-execute enable bit of the EFER.
+const EFER_NXE: u64 = 1 << 11;
+
+/// The write protect bit of CR0.
+const CR0_WP: usize = 1 << 16;
+
+/// The not write-through bit of CR0.
+const CR0_NW: usize = 1 << 29;
+
+/// The cache disable bit of CR0.
+const CR0_CD: usize = 1 << 30;
+
+/// The page global enable bit of CR4.
+const CR4_PGE: usize = 1 << 7;
+
+/// The process-context identifier enable bit of CR4.
+const CR4_PCIDE: usize = 1 << 17;
+
+/// The page attribute table MSR.
+const IA32_PAT: u32 = 0x277;
+
+/// Enables the no-execute page bit.
+///
+/// # Safety
+///
+/// No mapping may have the no-execute bit set before
+/// this is called, as the bit is reserved until then.
+pub unsafe fn enable_nxe() {
+    let efer = rdmsr(IA32_EFER);
+    wrmsr(IA32_EFER, efer | EFER_NXE);
+}
+
+/// Makes read-only pages read-only in kernel mode, too.
+///
+/// # Safety
+///
+/// The kernel must not write to read-only pages afterwards.
+pub unsafe fn enable_write_protect() {
+    let cr0: usize;
+    asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+    asm!("mov cr0, {}", in(reg) cr0 | CR0_WP, options(nostack));
+}
+
+/// Enables process-context identifiers.
+///
+/// # Safety
+///
+/// The PCID bits of CR3 must be zero.
+pub unsafe fn enable_pcid() {
+    let cr4: usize;
+    asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+    asm!("mov cr4, {}", in(reg) cr4 | CR4_PCIDE, options(nostack));
+}
+
+/// Programs the page attribute table.
+///
+/// Follows the sequence of the Intel SDM (11.12.4): caching is
+/// disabled and the caches and the whole TLB, including global
+/// entries, are flushed around the write, so no stale memory
+/// types survive.
+///
+/// # Safety
+///
+/// The CPU must support the page attribute table, and the
+/// entries the page tables select must keep their meaning.
+pub unsafe fn set_pat(value: u64) {
+    interrupts::without_interrupts(|| {
+        let (cr0, cr4): (usize, usize);
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+
+        // Enter no-fill cache mode and write back the caches
+        asm!("mov cr0, {}", in(reg) (cr0 | CR0_CD) & !CR0_NW, options(nostack));
+        asm!("wbinvd", options(nostack));
+        flush_tlb_all(cr4);
+
+        wrmsr(IA32_PAT, value);
+
+        asm!("wbinvd", options(nostack));
+        flush_tlb_all(cr4);
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack));
+    });
+}
+
+/// Flushes the whole TLB, including global entries.
+///
+/// Toggling CR4.PGE drops every entry; without global
+/// pages reloading CR3 is enough.
+///
+/// # Safety
+///
+/// The value must be the current content of CR4.
+unsafe fn flush_tlb_all(cr4: usize) {
+    if cr4 & CR4_PGE != 0 {
+        asm!("mov cr4, {}", in(reg) cr4 & !CR4_PGE, options(nostack));
+        asm!("mov cr4, {}", in(reg) cr4, options(nostack));
+    } else {
+        let cr3: usize;
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack));
+    }
+}
+
+/// Tests if the CPU supports process-context identifiers.
+pub fn has_pcid() -> bool {
+    (cpuid(0x1, 0).ecx & (1 << 17)) != 0
+}
+
+/// Tests if the CPU supports 1 GiB pages.
+pub fn has_1g_pages() -> bool {
+    (cpuid(0x80000001, 0).edx & (1 << 26)) != 0
+}
+
+/// Tests if the CPU supports the page attribute table.
+pub fn has_pat() -> bool {
+    (cpuid(0x1, 0).edx & (1 << 16)) != 0
+}
+
+/// Tests if the CPU has an on-chip local APIC.
+pub fn has_apic() -> bool {
+    (cpuid(0x1, 0).edx & (1 << 9)) != 0
+}
+
+/// Tests if the CPU supports x2APIC mode.
+pub fn has_x2apic() -> bool {
+    (cpuid(0x1, 0).ecx & (1 << 21)) != 0
+}
@@ -0,0 +1,274 @@
+use core::arch::asm;
+use core::ptr;
+use core::mem::size_of;
+use tss::{self, TaskStateSegment};
+
+/// The number of entries.
+const ENTRY_COUNT: usize = 8;
+
+// The segment descriptor bit flags
+bitflags! {
+    pub struct DescriptorFlags: u64 {
+        const WRITABLE =     1 << 41;
+        const CONFORMING =   1 << 42;
+        const EXECUTABLE =   1 << 43;
+        const USER_SEGMENT = 1 << 44;
+        const RING_3 =       3 << 45;
+        const PRESENT =      1 << 47;
+        const LONG_MODE =    1 << 53;
+    }
+}
+
+/// The type of an available 64-bit TSS descriptor.
+const TSS_TYPE_AVAILABLE: u64 = 0x9 << 40;
+
+/// The global descriptor table.
+static mut GDT: Gdt = Gdt::new();
+
+/// The selectors of the global descriptor table.
+static mut SELECTORS: Selectors = Selectors::new();
+
+/// The `PrivilegeLevel` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u16)]
+pub enum PrivilegeLevel {
+    Ring0 = 0,
+    Ring3 = 3,
+}
+
+/// The `SegmentSelector` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SegmentSelector(pub u16);
+
+/// The `SegmentSelector` implementation.
+impl SegmentSelector {
+    /// Constructs a new `SegmentSelector`.
+    pub const fn new(index: u16, rpl: PrivilegeLevel) -> SegmentSelector {
+        SegmentSelector(index << 3 | (rpl as u16))
+    }
+}
+
+/// The `Descriptor` type.
+///
+/// Represents a segment descriptor.
+pub enum Descriptor {
+    /// A code or data segment descriptor.
+    UserSegment(u64),
+
+    /// A system segment descriptor, which takes up two entries.
+    SystemSegment(u64, u64),
+}
+
+/// The `Descriptor` implementation.
+impl Descriptor {
+    /// Constructs a new kernel code segment `Descriptor`.
+    pub fn kernel_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+                    DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Constructs a new kernel data segment `Descriptor`.
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+                    DescriptorFlags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Constructs a new user code segment `Descriptor`.
+    pub fn user_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+                    DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE |
+                    DescriptorFlags::RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Constructs a new user data segment `Descriptor`.
+    pub fn user_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT |
+                    DescriptorFlags::WRITABLE | DescriptorFlags::RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    /// Constructs a new TSS `Descriptor`.
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        let base = tss as *const _ as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+        let low = DescriptorFlags::PRESENT.bits() | TSS_TYPE_AVAILABLE | (limit & 0xFFFF) |
+                  ((base & 0xFFFFFF) << 16) |
+                  (((limit >> 16) & 0xF) << 48) |
+                  (((base >> 24) & 0xFF) << 56);
+        let high = base >> 32;
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+/// The `DescriptorTablePointer` type.
+///
+/// Represents the operand of the `lgdt` instruction.
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    /// The size of the table minus one.
+    limit: u16,
+
+    /// The address of the table.
+    base: u64,
+}
+
+/// The `Gdt` type.
+///
+/// Represents the global descriptor table.
+pub struct Gdt {
+    /// The entries.
+    table: [u64; ENTRY_COUNT],
+
+    /// The index of the next free entry.
+    next_free: usize,
+}
+
+/// The `Gdt` implementation.
+impl Gdt {
+    /// Constructs a new `Gdt` containing only the null descriptor.
+    pub const fn new() -> Gdt {
+        Gdt {
+            table: [0; ENTRY_COUNT],
+            next_free: 1,
+        }
+    }
+
+    /// Adds a descriptor and returns its selector.
+    pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(low, high) => {
+                let index = self.push(low);
+                self.push(high);
+                index
+            }
+        };
+        let rpl = match entry {
+            Descriptor::UserSegment(value)
+                if value & DescriptorFlags::RING_3.bits() == DescriptorFlags::RING_3.bits() => {
+                PrivilegeLevel::Ring3
+            }
+            _ => PrivilegeLevel::Ring0,
+        };
+        SegmentSelector::new(index as u16, rpl)
+    }
+
+    /// Pushes a raw entry and returns its index.
+    fn push(&mut self, value: u64) -> usize {
+        let index = self.next_free;
+        assert!(index < ENTRY_COUNT, "GDT is full");
+        self.table[index] = value;
+        self.next_free += 1;
+        index
+    }
+
+    /// Loads the table into the GDT register and reloads
+    /// the segment registers with the specified selectors.
+    ///
+    /// The far return into the new code segment is required,
+    /// because `cs` cannot be written with a plain `mov`.
+    pub fn load(&'static self, code: SegmentSelector, data: SegmentSelector) {
+        let ptr = DescriptorTablePointer {
+            limit: (self.next_free * size_of::<u64>() - 1) as u16,
+            base: self.table.as_ptr() as u64,
+        };
+        unsafe {
+            asm!("lgdt [{}]", in(reg) &ptr, options(readonly, nostack, preserves_flags));
+
+            // Reload the data segment registers
+            asm!("mov ds, {0:x}",
+                 "mov es, {0:x}",
+                 "mov fs, {0:x}",
+                 "mov gs, {0:x}",
+                 "mov ss, {0:x}",
+                 in(reg) data.0,
+                 options(nostack, preserves_flags));
+
+            // Far return into the new code segment
+            asm!("push {}",
+                 "lea {tmp}, [rip + 2f]",
+                 "push {tmp}",
+                 "retfq",
+                 "2:",
+                 in(reg) code.0 as u64,
+                 tmp = lateout(reg) _,
+                 options(preserves_flags));
+        }
+    }
+}
+
+/// The `Default` implementation for `Gdt`.
+impl Default for Gdt {
+    fn default() -> Gdt {
+        Gdt::new()
+    }
+}
+
+/// The `Selectors` type.
+///
+/// Holds the selectors of the kernel's global descriptor table.
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub struct Selectors {
+    /// The kernel code segment selector.
+    pub kernel_code: SegmentSelector,
+
+    /// The kernel data segment selector.
+    pub kernel_data: SegmentSelector,
+
+    /// The user data segment selector.
+    pub user_data: SegmentSelector,
+
+    /// The user code segment selector.
+    pub user_code: SegmentSelector,
+
+    /// The task state segment selector.
+    pub tss: SegmentSelector,
+}
+
+/// The `Selectors` implementation.
+impl Selectors {
+    /// Constructs a new `Selectors` pointing to the null descriptor.
+    const fn new() -> Selectors {
+        Selectors {
+            kernel_code: SegmentSelector(0),
+            kernel_data: SegmentSelector(0),
+            user_data: SegmentSelector(0),
+            user_code: SegmentSelector(0),
+            tss: SegmentSelector(0),
+        }
+    }
+}
+
+/// Builds and loads the global descriptor table,
+/// replacing the bootstrap table from `gdt.asm`.
+///
+/// The user data segment precedes the user code segment,
+/// as required by the `sysret` instruction.
+pub fn init() {
+    let tss = tss::init();
+    unsafe {
+        let gdt = &mut *ptr::addr_of_mut!(GDT);
+        let selectors = Selectors {
+            kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+            user_data: gdt.add_entry(Descriptor::user_data_segment()),
+            user_code: gdt.add_entry(Descriptor::user_code_segment()),
+            tss: gdt.add_entry(Descriptor::tss_segment(tss)),
+        };
+        gdt.load(selectors.kernel_code, selectors.kernel_data);
+
+        // Load the task register
+        asm!("ltr {0:x}", in(reg) selectors.tss.0, options(nostack, preserves_flags));
+        SELECTORS = selectors;
+    }
+}
+
+/// Gets the selectors of the global descriptor table.
+#[allow(dead_code)]
+pub fn selectors() -> Selectors {
+    unsafe { SELECTORS }
+}
@@ -0,0 +1,203 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cpu::{self, rdmsr, wrmsr};
+use memory::mmio::{self, CacheMode};
+use super::irq::IRQ_BASE;
+
+/// The APIC base address MSR.
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// The global enable bit of the APIC base MSR.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The x2APIC enable bit of the APIC base MSR.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// The physical address bits of the APIC base MSR.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000ffffffffff000;
+
+/// The first x2APIC register MSR.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// The local APIC ID register.
+const REG_ID: u32 = 0x020;
+
+/// The task priority register.
+const REG_TASK_PRIORITY: u32 = 0x080;
+
+/// The end of interrupt register.
+const REG_END_OF_INTERRUPT: u32 = 0x0B0;
+
+/// The spurious interrupt vector register.
+const REG_SPURIOUS: u32 = 0x0F0;
+
+/// The timer local vector table register.
+const REG_LVT_TIMER: u32 = 0x320;
+
+/// The error local vector table register.
+const REG_LVT_ERROR: u32 = 0x370;
+
+/// The timer initial count register.
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+
+/// The timer current count register.
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+
+/// The timer divide configuration register.
+const REG_TIMER_DIVIDE: u32 = 0x3E0;
+
+/// The software enable bit of the spurious interrupt vector register.
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+/// The mask bit of the local vector table registers.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// The size of the xAPIC register page.
+const XAPIC_MMIO_SIZE: usize = 4096;
+
+/// The spurious interrupt vector.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The IRQ line the local APIC timer is delivered on.
+///
+/// The local APIC timer takes the place of the PIT.
+pub const TIMER_IRQ: u8 = 0;
+
+/// Whether the local APIC runs in x2APIC mode.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// The address of the xAPIC register page.
+static XAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The `TimerDivide` type.
+///
+/// Represents the divisor applied to the bus clock
+/// before it drives the timer.
+#[derive(Debug, Copy, Clone)]
+#[repr(u32)]
+#[allow(dead_code)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+/// The `TimerMode` type.
+#[derive(Debug, Copy, Clone)]
+#[repr(u32)]
+#[allow(dead_code)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1 << 17,
+}
+
+/// Reads a local APIC register.
+fn read(reg: u32) -> u32 {
+    unsafe {
+        if X2APIC.load(Ordering::Relaxed) {
+            rdmsr(X2APIC_MSR_BASE + (reg >> 4)) as u32
+        } else {
+            let addr = XAPIC_BASE.load(Ordering::Relaxed) + reg as usize;
+            ptr::read_volatile(addr as *const u32)
+        }
+    }
+}
+
+/// Writes a local APIC register.
+fn write(reg: u32, value: u32) {
+    unsafe {
+        if X2APIC.load(Ordering::Relaxed) {
+            wrmsr(X2APIC_MSR_BASE + (reg >> 4), value as u64);
+        } else {
+            let addr = XAPIC_BASE.load(Ordering::Relaxed) + reg as usize;
+            ptr::write_volatile(addr as *mut u32, value);
+        }
+    }
+}
+
+/// Initializes the local APIC.
+///
+/// Uses x2APIC mode when the CPU supports it and
+/// maps the xAPIC register page otherwise.
+pub fn init() {
+    let base = unsafe { rdmsr(IA32_APIC_BASE) };
+    if cpu::has_x2apic() {
+        X2APIC.store(true, Ordering::Relaxed);
+        // Going from disabled straight to x2APIC mode is invalid
+        unsafe {
+            wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+            wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+    } else {
+        let phys = (base & APIC_BASE_ADDRESS_MASK) as usize;
+        let mmio = mmio::ioremap(phys, XAPIC_MMIO_SIZE, CacheMode::Uncached)
+            .expect("Failed to map the local APIC");
+        XAPIC_BASE.store(mmio.leak(), Ordering::Relaxed);
+        unsafe {
+            wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        }
+    }
+
+    // Accept all interrupts and mask the local interrupt sources
+    write(REG_TASK_PRIORITY, 0);
+    write(REG_LVT_TIMER, LVT_MASKED | (IRQ_BASE + TIMER_IRQ) as u32);
+    write(REG_LVT_ERROR, LVT_MASKED);
+
+    // Software-enable the local APIC
+    write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// Gets the ID of the local APIC.
+pub fn id() -> u32 {
+    if X2APIC.load(Ordering::Relaxed) {
+        read(REG_ID)
+    } else {
+        read(REG_ID) >> 24
+    }
+}
+
+/// Signals the end of an interrupt.
+pub fn end_of_interrupt() {
+    write(REG_END_OF_INTERRUPT, 0);
+}
+
+/// Starts the timer.
+///
+/// The timer fires on `TIMER_IRQ` once it is unmasked.
+#[allow(dead_code)]
+pub fn start_timer(initial_count: u32, divide: TimerDivide, mode: TimerMode) {
+    let masked = read(REG_LVT_TIMER) & LVT_MASKED;
+    write(REG_TIMER_DIVIDE, divide as u32);
+    write(REG_LVT_TIMER, masked | mode as u32 | (IRQ_BASE + TIMER_IRQ) as u32);
+    write(REG_TIMER_INITIAL_COUNT, initial_count);
+}
+
+/// Stops the timer.
+#[allow(dead_code)]
+pub fn stop_timer() {
+    write(REG_TIMER_INITIAL_COUNT, 0);
+}
+
+/// Gets the current count of the timer.
+#[allow(dead_code)]
+pub fn timer_count() -> u32 {
+    read(REG_TIMER_CURRENT_COUNT)
+}
+
+/// Masks the timer interrupt.
+#[allow(dead_code)]
+pub fn mask_timer() {
+    let value = read(REG_LVT_TIMER);
+    write(REG_LVT_TIMER, value | LVT_MASKED);
+}
+
+/// Unmasks the timer interrupt.
+pub fn unmask_timer() {
+    let value = read(REG_LVT_TIMER);
+    write(REG_LVT_TIMER, value & !LVT_MASKED);
+}
@@ -0,0 +1,99 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use vga::{CONSOLE, Color, HalfColor};
+use serial::COM1;
+use memory::fault::{self, PageFaultCode};
+use super::InterruptFrame;
+
+/// The number of CPU exceptions.
+pub const EXCEPTION_COUNT: usize = 32;
+
+/// The non-maskable interrupt vector.
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+
+/// The double fault vector.
+pub const DOUBLE_FAULT: u8 = 8;
+
+/// The page fault vector.
+pub const PAGE_FAULT: u8 = 14;
+
+/// The names of the CPU exceptions.
+static EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// Handles a CPU exception.
+///
+/// Returns if a page fault could be resolved,
+/// otherwise reports the exception and halts the machine.
+pub fn handle(frame: &InterruptFrame) {
+    // Page faults store the faulting address in CR2
+    let page_fault = frame.vector == PAGE_FAULT as u64;
+    let code = PageFaultCode::from_bits_truncate(frame.error_code);
+    if page_fault && fault::handle_page_fault(read_cr2(), code) {
+        return;
+    }
+
+    CONSOLE.lock().set_color(Color::new(HalfColor::LightRed, HalfColor::Black));
+    report(format_args!("***\tCPU EXCEPTION\n\t{} (#{})\n\tError code: 0x{:x}\n",
+                        EXCEPTION_NAMES[frame.vector as usize],
+                        frame.vector,
+                        frame.error_code));
+    report(format_args!("\tRIP: 0x{:x}; CS: 0x{:x}\n\tRFLAGS: 0x{:x}; RSP: 0x{:x}\n",
+                        frame.rip,
+                        frame.cs,
+                        frame.rflags,
+                        frame.rsp));
+
+    if page_fault {
+        report(format_args!("\tCR2: 0x{:x}\n\t{}\n", read_cr2(), code));
+    }
+
+    super::halt()
+}
+
+/// Writes the arguments to the console and to COM1.
+fn report(args: fmt::Arguments) {
+    let _ = CONSOLE.lock().write_fmt(args);
+    let _ = COM1.lock().write_fmt(args);
+}
+
+/// Reads the faulting address from CR2.
+fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
@@ -0,0 +1,140 @@
+use core::arch::asm;
+use core::mem::size_of;
+
+/// The number of entries.
+pub const ENTRY_COUNT: usize = 256;
+
+/// The gate type of an interrupt gate.
+const GATE_INTERRUPT: u16 = 0xE << 8;
+
+/// The present bit.
+const GATE_PRESENT: u16 = 1 << 15;
+
+/// The `Entry` type.
+///
+/// Represents a 64-bit interrupt gate descriptor.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct Entry {
+    /// Bits 0..16 of the handler address.
+    offset_low: u16,
+
+    /// The code segment selector.
+    selector: u16,
+
+    /// The IST index, gate type, privilege level and present bit.
+    options: u16,
+
+    /// Bits 16..32 of the handler address.
+    offset_mid: u16,
+
+    /// Bits 32..64 of the handler address.
+    offset_high: u32,
+
+    /// Reserved.
+    reserved: u32,
+}
+
+/// The `Entry` implementation.
+impl Entry {
+    /// Constructs a new missing `Entry`.
+    pub const fn missing() -> Entry {
+        Entry {
+            offset_low: 0,
+            selector: 0,
+            options: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    /// Constructs a new present interrupt gate
+    /// for the specified handler address.
+    pub fn new(selector: u16, handler: usize) -> Entry {
+        Entry {
+            offset_low: handler as u16,
+            selector,
+            options: GATE_INTERRUPT | GATE_PRESENT,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+
+    /// Sets the interrupt stack table index.
+    ///
+    /// The CPU switches to the stack stored at this
+    /// index of the TSS before invoking the handler.
+    pub fn set_stack_index(&mut self, index: usize) {
+        self.options = (self.options & !0b111) | (index as u16 + 1);
+    }
+}
+
+/// The `DescriptorTablePointer` type.
+///
+/// Represents the operand of the `lidt` instruction.
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    /// The size of the table minus one.
+    limit: u16,
+
+    /// The address of the table.
+    base: u64,
+}
+
+/// The `Idt` type.
+///
+/// Represents the interrupt descriptor table.
+pub struct Idt {
+    /// The entries.
+    entries: [Entry; ENTRY_COUNT],
+}
+
+/// The `Idt` implementation.
+impl Idt {
+    /// Constructs a new `Idt` with all entries missing.
+    pub const fn new() -> Idt {
+        Idt { entries: [Entry::missing(); ENTRY_COUNT] }
+    }
+
+    /// Sets the handler address of the specified vector.
+    pub fn set_handler(&mut self, vector: u8, handler: usize) {
+        let selector = code_selector();
+        self.entries[vector as usize] = Entry::new(selector, handler);
+    }
+
+    /// Sets the interrupt stack table index of the specified vector.
+    pub fn set_stack_index(&mut self, vector: u8, index: usize) {
+        self.entries[vector as usize].set_stack_index(index);
+    }
+
+    /// Loads the table into the IDT register.
+    ///
+    /// The table must not move while it is loaded.
+    pub fn load(&self) {
+        let ptr = DescriptorTablePointer {
+            limit: (size_of::<Idt>() - 1) as u16,
+            base: self as *const _ as u64,
+        };
+        unsafe {
+            asm!("lidt [{}]", in(reg) &ptr, options(readonly, nostack, preserves_flags));
+        }
+    }
+}
+
+/// The `Default` implementation for `Idt`.
+impl Default for Idt {
+    fn default() -> Idt {
+        Idt::new()
+    }
+}
+
+/// Gets the current code segment selector.
+fn code_selector() -> u16 {
+    let selector: u16;
+    unsafe {
+        asm!("mov {0:x}, cs", out(reg) selector, options(nomem, nostack, preserves_flags));
+    }
+    selector
+}
@@ -0,0 +1,216 @@
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use memory::mmio::{self, CacheMode};
+use super::irq::{IRQ_BASE, IRQ_COUNT};
+use super::madt::Madt;
+use super::without_interrupts;
+
+/// The size of the I/O APIC register window.
+const IOAPIC_MMIO_SIZE: usize = 4096;
+
+/// The register select register.
+const IOREGSEL: usize = 0x00;
+
+/// The register window register.
+const IOWIN: usize = 0x10;
+
+/// The version register.
+const REG_VERSION: u32 = 0x01;
+
+/// The first redirection table register.
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+/// The mask bit of a redirection entry.
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The address of the I/O APIC.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of redirection entries.
+static ENTRY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The pin marking an IRQ without a route.
+const NO_PIN: u8 = 0xFF;
+
+/// The pins the IRQs are routed to.
+static PINS: Mutex<[u8; IRQ_COUNT]> = Mutex::new([NO_PIN; IRQ_COUNT]);
+
+/// The `Polarity` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// The `TriggerMode` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// The `RedirectionEntry` type.
+///
+/// Represents a fixed delivery, physical destination
+/// entry of the redirection table.
+#[derive(Debug, Copy, Clone)]
+pub struct RedirectionEntry {
+    /// The vector.
+    pub vector: u8,
+
+    /// The pin polarity.
+    pub polarity: Polarity,
+
+    /// The trigger mode.
+    pub trigger_mode: TriggerMode,
+
+    /// Whether the pin is masked.
+    pub masked: bool,
+
+    /// The APIC ID of the destination.
+    pub destination: u8,
+}
+
+/// The `RedirectionEntry` implementation.
+impl RedirectionEntry {
+    /// Encodes the entry into its register value.
+    fn bits(&self) -> u64 {
+        let mut value = self.vector as u64;
+        if let Polarity::ActiveLow = self.polarity {
+            value |= 1 << 13;
+        }
+        if let TriggerMode::Level = self.trigger_mode {
+            value |= 1 << 15;
+        }
+        if self.masked {
+            value |= REDIRECTION_MASKED;
+        }
+        value | ((self.destination as u64) << 56)
+    }
+}
+
+/// Reads an I/O APIC register.
+fn read(reg: u32) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        ptr::write_volatile((base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((base + IOWIN) as *const u32)
+    }
+}
+
+/// Writes an I/O APIC register.
+fn write(reg: u32, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        ptr::write_volatile((base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((base + IOWIN) as *mut u32, value);
+    }
+}
+
+/// Reads a redirection entry.
+fn read_entry(pin: u8) -> u64 {
+    let reg = REG_REDIRECTION_TABLE + pin as u32 * 2;
+    (read(reg) as u64) | ((read(reg + 1) as u64) << 32)
+}
+
+/// Writes a redirection entry.
+fn write_entry(pin: u8, value: u64) {
+    let reg = REG_REDIRECTION_TABLE + pin as u32 * 2;
+    // Write the masked low half first so the entry never
+    // fires with a half-written destination
+    write(reg, (value as u32) | REDIRECTION_MASKED as u32);
+    write(reg + 1, (value >> 32) as u32);
+    write(reg, value as u32);
+}
+
+/// Initializes the I/O APIC described by the MADT.
+///
+/// Routes every ISA IRQ to its vector following `IRQ_BASE` on the
+/// specified local APIC, honouring the interrupt source overrides.
+/// All pins are masked.
+pub fn init(destination: u32, madt: &Madt) {
+    let mmio = mmio::ioremap(madt.ioapic_base, IOAPIC_MMIO_SIZE, CacheMode::Uncached)
+        .expect("Failed to map the I/O APIC");
+    BASE.store(mmio.leak(), Ordering::Relaxed);
+
+    // Get the number of redirection entries
+    let count = ((read(REG_VERSION) >> 16) & 0xFF) as usize + 1;
+    ENTRY_COUNT.store(count, Ordering::Relaxed);
+
+    for pin in 0..count {
+        set_entry(pin as u8,
+                  RedirectionEntry {
+                      vector: IRQ_BASE + pin as u8,
+                      polarity: Polarity::ActiveHigh,
+                      trigger_mode: TriggerMode::Edge,
+                      masked: true,
+                      destination: destination as u8,
+                  });
+    }
+
+    // Route the ISA IRQs
+    without_interrupts(|| {
+        let mut pins = PINS.lock();
+        for (irq, route) in madt.routes.iter().enumerate() {
+            let route = match *route {
+                Some(route) if route.gsi >= madt.gsi_base => route,
+                _ => continue,
+            };
+            let pin = (route.gsi - madt.gsi_base) as usize;
+            if pin >= count {
+                continue;
+            }
+            set_entry(pin as u8,
+                      RedirectionEntry {
+                          vector: IRQ_BASE + irq as u8,
+                          polarity: route.polarity,
+                          trigger_mode: route.trigger_mode,
+                          masked: true,
+                          destination: destination as u8,
+                      });
+            pins[irq] = pin as u8;
+        }
+    });
+}
+
+/// Gets the number of redirection entries.
+pub fn entry_count() -> usize {
+    ENTRY_COUNT.load(Ordering::Relaxed)
+}
+
+/// Programs the redirection entry of the specified pin.
+pub fn set_entry(pin: u8, entry: RedirectionEntry) {
+    assert!((pin as usize) < entry_count());
+    write_entry(pin, entry.bits());
+}
+
+/// Gets the pin the specified IRQ is routed to.
+fn irq_pin(irq: u8) -> Option<u8> {
+    let pin = without_interrupts(|| PINS.lock()[irq as usize]);
+    if pin == NO_PIN {
+        None
+    } else {
+        Some(pin)
+    }
+}
+
+/// Masks the pin of the specified IRQ.
+pub fn mask(irq: u8) {
+    if let Some(pin) = irq_pin(irq) {
+        let value = read_entry(pin);
+        write_entry(pin, value | REDIRECTION_MASKED);
+    }
+}
+
+/// Unmasks the pin of the specified IRQ.
+///
+/// IRQs without a route stay masked.
+pub fn unmask(irq: u8) {
+    if let Some(pin) = irq_pin(irq) {
+        let value = read_entry(pin);
+        write_entry(pin, value & !REDIRECTION_MASKED);
+    }
+}
@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use cpu;
+use multiboot2;
+use super::{pic, apic, ioapic, without_interrupts};
+use super::madt::Madt;
+
+/// The number of IRQ lines.
+pub const IRQ_COUNT: usize = 16;
+
+/// The vector of the first IRQ.
+pub const IRQ_BASE: u8 = pic::PIC1_OFFSET;
+
+/// The `IrqHandler` type.
+pub type IrqHandler = fn();
+
+/// The registered IRQ handlers.
+static HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Whether IRQs are routed through the APIC instead of the PIC.
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
+/// Initializes the interrupt controller.
+///
+/// Uses the local and I/O APIC when the CPU has one,
+/// and falls back to the 8259 PIC otherwise. The I/O APIC
+/// is found through the MADT of the multiboot2 ACPI tables.
+pub fn init(multiboot2_addr: usize) {
+    // Remap the PIC even when it goes unused,
+    // so spurious IRQs can't show up as exceptions
+    pic::init();
+
+    if cpu::has_apic() {
+        pic::disable();
+        apic::init();
+        let mb2_info = unsafe { multiboot2::load(multiboot2_addr) };
+        ioapic::init(apic::id(), &Madt::parse(&mb2_info));
+        USING_APIC.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Tests if IRQs are routed through the APIC.
+pub fn using_apic() -> bool {
+    USING_APIC.load(Ordering::Relaxed)
+}
+
+/// Masks the specified IRQ line.
+#[allow(dead_code)]
+pub fn mask(irq: u8) {
+    if !using_apic() {
+        pic::mask(irq);
+    } else if irq == apic::TIMER_IRQ {
+        apic::mask_timer();
+    } else {
+        ioapic::mask(irq);
+    }
+}
+
+/// Unmasks the specified IRQ line.
+pub fn unmask(irq: u8) {
+    if !using_apic() {
+        pic::unmask(irq);
+    } else if irq == apic::TIMER_IRQ {
+        apic::unmask_timer();
+    } else {
+        ioapic::unmask(irq);
+    }
+}
+
+/// Registers a handler for the specified IRQ line
+/// and unmasks the line.
+pub fn register(irq: u8, handler: IrqHandler) {
+    assert!((irq as usize) < IRQ_COUNT);
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        assert!(handlers[irq as usize].is_none(),
+                "IRQ {} already has a handler",
+                irq);
+        handlers[irq as usize] = Some(handler);
+        unmask(irq);
+    });
+}
+
+/// Tests if the specified IRQ line has a handler.
+pub fn is_registered(irq: u8) -> bool {
+    assert!((irq as usize) < IRQ_COUNT);
+    without_interrupts(|| HANDLERS.lock()[irq as usize].is_some())
+}
+
+/// Unregisters the handler of the specified IRQ line
+/// and masks the line.
+#[allow(dead_code)]
+pub fn unregister(irq: u8) {
+    assert!((irq as usize) < IRQ_COUNT);
+    without_interrupts(|| {
+        mask(irq);
+        HANDLERS.lock()[irq as usize] = None;
+    });
+}
+
+/// Dispatches an IRQ to its handler.
+pub fn dispatch(irq: u8) {
+    if !using_apic() && pic::is_spurious(irq) {
+        return;
+    }
+
+    // Copy the handler so the lock is not held while it runs
+    let handler = HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    if using_apic() {
+        apic::end_of_interrupt();
+    } else {
+        pic::end_of_interrupt(irq);
+    }
+}
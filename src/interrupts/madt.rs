@@ -0,0 +1,231 @@
+use acpi;
+use memory::paging::PhysicalAddress;
+use multiboot2::BootInformation;
+use super::ioapic::{Polarity, TriggerMode};
+use super::irq::IRQ_COUNT;
+
+/// The default physical address of the I/O APIC.
+const IOAPIC_DEFAULT_BASE: PhysicalAddress = 0xFEC00000;
+
+/// The offset of the first entry.
+const ENTRIES_OFFSET: usize = 44;
+
+/// The I/O APIC entry type.
+const ENTRY_IOAPIC: u8 = 1;
+
+/// The interrupt source override entry type.
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+
+/// The `IrqRoute` type.
+///
+/// Represents how an ISA IRQ is wired to the I/O APIC.
+#[derive(Debug, Copy, Clone)]
+pub struct IrqRoute {
+    /// The global system interrupt.
+    pub gsi: u32,
+
+    /// The pin polarity.
+    pub polarity: Polarity,
+
+    /// The trigger mode.
+    pub trigger_mode: TriggerMode,
+}
+
+/// The `IrqRoute` implementation.
+impl IrqRoute {
+    /// Constructs the route of an ISA IRQ that isn't overridden,
+    /// which is identity mapped, active high and edge triggered.
+    fn isa(irq: u8) -> IrqRoute {
+        IrqRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }
+    }
+}
+
+/// The `Madt` type.
+///
+/// Holds what the multiple APIC description table says
+/// about the I/O APIC handling the ISA IRQs.
+#[derive(Debug, Copy, Clone)]
+pub struct Madt {
+    /// The physical address of the I/O APIC.
+    pub ioapic_base: PhysicalAddress,
+
+    /// The first global system interrupt of the I/O APIC.
+    pub gsi_base: u32,
+
+    /// The routes of the ISA IRQs.
+    ///
+    /// IRQs whose interrupt is taken by an override have no route.
+    pub routes: [Option<IrqRoute>; IRQ_COUNT],
+}
+
+/// The `Madt` implementation.
+impl Madt {
+    /// Constructs the `Madt` assumed without the table.
+    ///
+    /// Places the I/O APIC at its default address,
+    /// with the ISA IRQs identity mapped to its pins.
+    pub fn fallback() -> Madt {
+        let mut routes = [None; IRQ_COUNT];
+        for (irq, route) in routes.iter_mut().enumerate() {
+            *route = Some(IrqRoute::isa(irq as u8));
+        }
+        Madt {
+            ioapic_base: IOAPIC_DEFAULT_BASE,
+            gsi_base: 0,
+            routes,
+        }
+    }
+
+    /// Parses the MADT.
+    ///
+    /// Uses the I/O APIC handling global system interrupt 0 and
+    /// applies the interrupt source overrides, e.g. IRQ 0 arriving
+    /// at interrupt 2. Falls back to `Madt::fallback` without the table.
+    pub fn parse(mb2_info: &BootInformation) -> Madt {
+        let mut madt = Madt::fallback();
+        let table = match acpi::find_table(mb2_info, b"APIC") {
+            Some(table) => table,
+            None => return madt,
+        };
+
+        let mut overridden = [false; IRQ_COUNT];
+        let mut entry = ENTRIES_OFFSET;
+        while entry + 2 <= table.len() {
+            let typ = acpi::read::<u8>(&table, entry);
+            let length = acpi::read::<u8>(&table, entry + 1) as usize;
+            if length < 2 || entry + length > table.len() {
+                break;
+            }
+            match typ {
+                ENTRY_IOAPIC => {
+                    let gsi_base = acpi::read::<u32>(&table, entry + 8);
+                    if gsi_base == 0 {
+                        madt.ioapic_base = acpi::read::<u32>(&table, entry + 4) as PhysicalAddress;
+                    }
+                }
+                ENTRY_SOURCE_OVERRIDE => {
+                    let source = acpi::read::<u8>(&table, entry + 3) as usize;
+                    let gsi = acpi::read::<u32>(&table, entry + 4);
+                    let flags = acpi::read::<u16>(&table, entry + 8);
+                    madt.override_route(&mut overridden, source, gsi, flags);
+                }
+                _ => (),
+            }
+            entry += length;
+        }
+
+        madt.drop_taken_routes(&overridden);
+        madt
+    }
+
+    /// Routes an ISA IRQ as an interrupt source override says.
+    ///
+    /// Ignores overrides of sources that aren't ISA IRQs.
+    fn override_route(&mut self,
+                      overridden: &mut [bool; IRQ_COUNT],
+                      source: usize,
+                      gsi: u32,
+                      flags: u16) {
+        if source >= IRQ_COUNT {
+            return;
+        }
+
+        // Flags of 0 conform to the ISA defaults
+        self.routes[source] = Some(IrqRoute {
+            gsi,
+            polarity: match flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            },
+            trigger_mode: match (flags >> 2) & 0b11 {
+                0b11 => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            },
+        });
+        overridden[source] = true;
+    }
+
+    /// Drops the identity routes of the interrupts taken by overrides.
+    fn drop_taken_routes(&mut self, overridden: &[bool; IRQ_COUNT]) {
+        for irq in 0..IRQ_COUNT {
+            let taken = (0..IRQ_COUNT).any(|source| {
+                source != irq && overridden[source] &&
+                self.routes[source].map(|route| route.gsi) == Some(irq as u32)
+            });
+            if taken && !overridden[irq] {
+                self.routes[irq] = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the routes left by the specified overrides.
+    fn madt(overrides: &[(usize, u32, u16)]) -> Madt {
+        let mut madt = Madt::fallback();
+        let mut overridden = [false; IRQ_COUNT];
+        for &(source, gsi, flags) in overrides {
+            madt.override_route(&mut overridden, source, gsi, flags);
+        }
+        madt.drop_taken_routes(&overridden);
+        madt
+    }
+
+    /// Gets the interrupt an IRQ is routed to.
+    fn gsi(madt: &Madt, irq: usize) -> Option<u32> {
+        madt.routes[irq].map(|route| route.gsi)
+    }
+
+    #[test]
+    fn fallback_identity_maps_the_isa_irqs() {
+        let madt = madt(&[]);
+        for irq in 0..IRQ_COUNT {
+            let route = madt.routes[irq].unwrap();
+            assert_eq!(route.gsi, irq as u32);
+            assert_eq!(route.polarity, Polarity::ActiveHigh);
+            assert_eq!(route.trigger_mode, TriggerMode::Edge);
+        }
+    }
+
+    #[test]
+    fn override_drops_the_route_of_the_taken_interrupt() {
+        let madt = madt(&[(0, 2, 0)]);
+        assert_eq!(gsi(&madt, 0), Some(2));
+        assert_eq!(gsi(&madt, 2), None);
+        assert_eq!(gsi(&madt, 1), Some(1));
+    }
+
+    #[test]
+    fn override_applies_polarity_and_trigger_mode() {
+        let madt = madt(&[(9, 9, 0b1111), (10, 10, 0b0101)]);
+        let route = madt.routes[9].unwrap();
+        assert_eq!(route.gsi, 9);
+        assert_eq!(route.polarity, Polarity::ActiveLow);
+        assert_eq!(route.trigger_mode, TriggerMode::Level);
+        let route = madt.routes[10].unwrap();
+        assert_eq!(route.polarity, Polarity::ActiveHigh);
+        assert_eq!(route.trigger_mode, TriggerMode::Edge);
+    }
+
+    #[test]
+    fn overridden_irqs_keep_their_routes() {
+        let madt = madt(&[(0, 2, 0), (2, 0, 0)]);
+        assert_eq!(gsi(&madt, 0), Some(2));
+        assert_eq!(gsi(&madt, 2), Some(0));
+    }
+
+    #[test]
+    fn override_of_a_non_isa_source_is_ignored() {
+        let madt = madt(&[(IRQ_COUNT, 3, 0b1111)]);
+        for irq in 0..IRQ_COUNT {
+            assert_eq!(gsi(&madt, irq), Some(irq as u32));
+        }
+    }
+}
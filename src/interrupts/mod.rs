@@ -0,0 +1,159 @@
+use core::arch::asm;
+use spin::Mutex;
+
+mod idt;
+mod exceptions;
+pub mod pic;
+pub mod apic;
+pub mod ioapic;
+pub mod madt;
+pub mod irq;
+
+use tss::{DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX};
+use self::idt::{Idt, ENTRY_COUNT};
+use self::exceptions::{EXCEPTION_COUNT, DOUBLE_FAULT, NON_MASKABLE_INTERRUPT};
+use self::irq::{IRQ_BASE, IRQ_COUNT};
+
+/// The interrupt descriptor table.
+static IDT: Mutex<Idt> = Mutex::new(Idt::new());
+
+extern "C" {
+    /// The addresses of the interrupt stubs in `interrupts.asm`.
+    static isr_stub_table: [usize; ENTRY_COUNT];
+}
+
+/// The `InterruptFrame` type.
+///
+/// Represents the stack layout built by the interrupt stubs.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// The interrupt vector.
+    pub vector: u64,
+
+    /// The error code, or zero if the CPU pushed none.
+    pub error_code: u64,
+
+    /// The interrupted instruction pointer.
+    pub rip: u64,
+
+    /// The interrupted code segment.
+    pub cs: u64,
+
+    /// The interrupted flags.
+    pub rflags: u64,
+
+    /// The interrupted stack pointer.
+    pub rsp: u64,
+
+    /// The interrupted stack segment.
+    pub ss: u64,
+}
+
+/// Initializes the interrupt descriptor table.
+pub fn init() {
+    let mut idt = IDT.lock();
+    let stubs = unsafe { &isr_stub_table };
+    for (vector, &stub) in stubs.iter().enumerate() {
+        idt.set_handler(vector as u8, stub);
+    }
+
+    // Run the double fault and NMI handlers on known-good stacks
+    idt.set_stack_index(DOUBLE_FAULT, DOUBLE_FAULT_IST_INDEX);
+    idt.set_stack_index(NON_MASKABLE_INTERRUPT, NMI_IST_INDEX);
+    idt.load();
+}
+
+/// Dispatches an interrupt.
+///
+/// Called by the interrupt stubs in `interrupts.asm`.
+/// Spurious APIC interrupts must not be acknowledged,
+/// so they fall through without an EOI.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let vector = frame.vector as u8;
+    if (vector as usize) < EXCEPTION_COUNT {
+        exceptions::handle(frame);
+    } else if vector >= IRQ_BASE && ((vector - IRQ_BASE) as usize) < IRQ_COUNT {
+        irq::dispatch(vector - IRQ_BASE);
+    }
+}
+
+/// Enables interrupts.
+#[inline(always)]
+pub fn enable() {
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+/// Disables interrupts.
+#[inline(always)]
+pub fn disable() {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+/// Enables interrupts and halts until the next one arrives.
+///
+/// `sti` only takes effect after the following instruction,
+/// so no interrupt can slip in between the two.
+#[inline(always)]
+pub fn enable_and_halt() {
+    unsafe {
+        asm!("sti", "hlt", options(nomem, nostack));
+    }
+}
+
+/// Tests if interrupts are enabled.
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    (rflags & (1 << 9)) != 0
+}
+
+/// Runs the closure with interrupts disabled.
+///
+/// Restores the previous interrupt state afterwards.
+pub fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
+
+/// Disables interrupts and halts forever.
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt", options(nomem, nostack));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use cpu::{inb, outb};
+
+/// The command port of the master PIC.
+const PIC1_COMMAND: u16 = 0x20;
+
+/// The data port of the master PIC.
+const PIC1_DATA: u16 = 0x21;
+
+/// The command port of the slave PIC.
+const PIC2_COMMAND: u16 = 0xA0;
+
+/// The data port of the slave PIC.
+const PIC2_DATA: u16 = 0xA1;
+
+/// The vector offset of the master PIC.
+pub const PIC1_OFFSET: u8 = 32;
+
+/// The vector offset of the slave PIC.
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+/// The IRQ line the slave PIC is cascaded on.
+const CASCADE_IRQ: u8 = 2;
+
+/// Initialization command word 1: start initialization, expect ICW4.
+const ICW1_INIT: u8 = 0x11;
+
+/// Initialization command word 4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+
+/// The end of interrupt command.
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+
+/// The read in-service register command.
+const CMD_READ_ISR: u8 = 0x0B;
+
+/// Waits for the PIC to process a command.
+///
+/// Writing to the unused port 0x80 takes long enough
+/// for the PIC to catch up on older hardware.
+#[inline(always)]
+fn io_wait() {
+    unsafe {
+        outb(0, 0x80);
+    }
+}
+
+/// Initializes both PICs.
+///
+/// Remaps the IRQs to the vectors following the CPU
+/// exceptions and masks every line except the cascade.
+pub fn init() {
+    unsafe {
+        // Start the initialization sequence
+        outb(ICW1_INIT, PIC1_COMMAND);
+        io_wait();
+        outb(ICW1_INIT, PIC2_COMMAND);
+        io_wait();
+
+        // Set the vector offsets
+        outb(PIC1_OFFSET, PIC1_DATA);
+        io_wait();
+        outb(PIC2_OFFSET, PIC2_DATA);
+        io_wait();
+
+        // Tell the master about the slave and the slave its cascade identity
+        outb(1 << CASCADE_IRQ, PIC1_DATA);
+        io_wait();
+        outb(CASCADE_IRQ, PIC2_DATA);
+        io_wait();
+
+        // Set 8086 mode
+        outb(ICW4_8086, PIC1_DATA);
+        io_wait();
+        outb(ICW4_8086, PIC2_DATA);
+        io_wait();
+
+        // Mask every line except the cascade
+        outb(!(1 << CASCADE_IRQ), PIC1_DATA);
+        outb(0xFF, PIC2_DATA);
+    }
+}
+
+/// Masks every line of both PICs.
+pub fn disable() {
+    unsafe {
+        outb(0xFF, PIC1_DATA);
+        outb(0xFF, PIC2_DATA);
+    }
+}
+
+/// Gets the data port and bit of the specified IRQ line.
+fn line(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+/// Masks the specified IRQ line.
+pub fn mask(irq: u8) {
+    let (port, bit) = line(irq);
+    unsafe {
+        let value = inb(port) | (1 << bit);
+        outb(value, port);
+    }
+}
+
+/// Unmasks the specified IRQ line.
+pub fn unmask(irq: u8) {
+    let (port, bit) = line(irq);
+    unsafe {
+        let value = inb(port) & !(1 << bit);
+        outb(value, port);
+    }
+}
+
+/// Tests if the specified IRQ line is currently being serviced.
+fn in_service(irq: u8) -> bool {
+    let (port, bit) = line(irq);
+    let command = port - 1;
+    unsafe {
+        outb(CMD_READ_ISR, command);
+        (inb(command) & (1 << bit)) != 0
+    }
+}
+
+/// Tests if the specified IRQ is spurious.
+///
+/// Spurious IRQs show up on the lowest priority line of
+/// either PIC (7 or 15) without their in-service bit set.
+/// A spurious IRQ 15 still needs an EOI on the master,
+/// because the master cannot tell it apart from a real one.
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    if in_service(irq) {
+        return false;
+    }
+    if irq == 15 {
+        unsafe {
+            outb(CMD_END_OF_INTERRUPT, PIC1_COMMAND);
+        }
+    }
+    true
+}
+
+/// Signals the end of the specified IRQ.
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(CMD_END_OF_INTERRUPT, PIC2_COMMAND);
+        }
+        outb(CMD_END_OF_INTERRUPT, PIC1_COMMAND);
+    }
+}
@@ -1,39 +1,57 @@
-#![feature(lang_items)]
-#![feature(const_fn)]
-#![feature(unique)]
-#![feature(asm)]
-#![no_std]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
+#![cfg_attr(not(test), no_std)]
 
-extern crate rlibc;
+#[cfg(test)]
+extern crate core;
 extern crate spin;
-extern crate cpuio;
-extern crate multiboot2;
+extern crate alloc;
 
 #[macro_use]
 extern crate bitflags;
 
 use core::fmt::Write;
+#[cfg(not(test))]
+use core::panic::PanicInfo;
 
 #[macro_use]
 mod vga;
-use vga::{Console, Color, HalfColor};
+#[cfg(not(test))]
+use vga::{Color, HalfColor};
+use vga::CONSOLE;
+mod ring_buffer;
 mod serial;
-use serial::COM1;
+use serial::{COM1, SerialConfig};
+mod multiboot2;
+mod acpi;
 mod memory;
-use memory::FrameAllocator;
-
-#[lang = "eh_personality"]
-extern "C" fn eh_personality() {}
-
-#[lang = "panic_fmt"]
-extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &str, line: u32) -> ! {
-    Console.lock().set_cursor(0, 0);
-    Console.lock().set_color(Color::new(HalfColor::LightRed, HalfColor::Black));
-    println!("***\tKERNEL PANIC\n\tin {} at line {}:\n\t{}",
-             file,
-             line,
-             fmt);
-    loop {}
+mod cpu;
+mod tss;
+mod gdt;
+mod interrupts;
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    CONSOLE.lock().set_cursor(0, 0);
+    CONSOLE.lock().set_color(Color::new(HalfColor::LightRed, HalfColor::Black));
+    match info.location() {
+        Some(location) => {
+            println!("***\tKERNEL PANIC\n\tin {} at line {}:\n\t{}",
+                     location.file(),
+                     location.line(),
+                     info.message())
+        }
+        None => println!("***\tKERNEL PANIC\n\t{}", info.message()),
+    }
+    interrupts::halt()
+}
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    panic!("Out of heap memory allocating {} bytes aligned to {}",
+           layout.size(),
+           layout.align());
 }
 
 /// Early kernel entry point.
@@ -41,37 +59,53 @@ extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &str, line: u32) -> ! {
 pub extern "C" fn kmain_setup(multiboot2_addr: usize) {
 
     // Clear the VGA buffer
-    Console.lock().clear_screen();
+    CONSOLE.lock().clear_screen();
+
+    // Initialize COM1
+    if let Err(err) = COM1.lock().init(SerialConfig::new()) {
+        println!("COM1: {:?}", err);
+    }
+
+    // Load the global descriptor table
+    gdt::init();
+
+    // Install the CPU exception handlers
+    interrupts::init();
+
+    // Initialize the memory controller
+    memory::init(multiboot2_addr);
+
+    // Initialize the interrupt controller
+    interrupts::irq::init(multiboot2_addr);
+
+    // Make COM1 interrupt-driven
+    COM1.lock().enable_interrupts();
 
     // Print multiboot2 debug information
     debug_print_multiboot2_info(multiboot2_addr);
 
-    // Initialize COM1
-    COM1.lock().init();
-
     // Test the serial writer
-    write!(COM1.lock(), "Hello, world!");
+    let _ = write!(COM1.lock(), "Hello, world!");
+
+    // Start accepting IRQs
+    interrupts::enable();
 }
 
 /// Main kernel entry point.
 #[no_mangle]
 pub extern "C" fn kmain() -> ! {
     println!("Hello from Rite!");
-    loop {}
+    loop {
+        interrupts::enable_and_halt();
+    }
 }
 
 fn debug_print_multiboot2_info(multiboot2_addr: usize) {
     // Get the multiboot2 data
     let mb2_info = unsafe { multiboot2::load(multiboot2_addr) };
 
-    // Get the memory mapping and print the memory areas
-    let memory_map = mb2_info.memory_map_tag().unwrap();
-    println!("Memory areas:");
-    for area in memory_map.memory_areas() {
-        println!("\tStart: 0x{:x}; Length: 0x{:x}",
-                 area.base_addr,
-                 area.length);
-    }
+    // Print the normalised memory map
+    memory::with_controller(|mc| println!("Memory map:\n{}", mc.memory_map));
 
     // Get the elf sections and print them
     let elf_sections = mb2_info.elf_sections_tag().unwrap();
@@ -83,10 +117,16 @@ fn debug_print_multiboot2_info(multiboot2_addr: usize) {
                  section.flags);
     }
 
-    // Get the kernel and multiboo2 memory bounds and print them
-    let kernel_start = elf_sections.sections().map(|s| s.addr).min().unwrap();
-    let kernel_end = elf_sections.sections().map(|s| s.addr + s.size).max().unwrap();
-    let mb2_start = multiboot2_addr;
+    // Get the physical kernel and multiboo2 memory bounds and print them
+    let kernel_start = elf_sections.sections()
+        .map(|s| memory::section_phys_addr(s.addr as usize))
+        .min()
+        .unwrap();
+    let kernel_end = elf_sections.sections()
+        .map(|s| memory::section_phys_addr((s.addr + s.size) as usize))
+        .max()
+        .unwrap();
+    let mb2_start = memory::virt_to_phys(multiboot2_addr);
     let mb2_end = mb2_start + (mb2_info.total_size as usize);
     println!("Kernel start: 0x{:x}\nKernel end: 0x{:x}",
              kernel_start,
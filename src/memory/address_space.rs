@@ -0,0 +1,441 @@
+use core::{cmp, slice};
+use memory::PAGE_SIZE;
+use memory::paging::{VirtualAddress, EntryFlags, MapError};
+
+/// The maximum number of regions per address space.
+///
+/// Adding or splitting a region beyond it fails
+/// with `VmaError::TooManyRegions`.
+pub const MAX_VIRTUAL_REGIONS: usize = 64;
+
+/// The `VmaError` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VmaError {
+    /// The range overlaps a region.
+    Overlap,
+
+    /// No free range of the window is large enough.
+    OutOfSpace,
+
+    /// The range is not entirely covered by regions.
+    NotReserved,
+
+    /// The range overlaps a region owned by someone else.
+    FixedRegion,
+
+    /// The range is empty.
+    EmptyRange,
+
+    /// The range or address is not page aligned.
+    Unaligned,
+
+    /// The address space would hold more
+    /// than `MAX_VIRTUAL_REGIONS` regions.
+    TooManyRegions,
+
+    /// Mapping a page failed.
+    Map(MapError),
+}
+
+/// The `Placement` enum.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Placement {
+    /// The lowest free range of the window.
+    FirstFit,
+
+    /// The highest free range of the window.
+    TopDown,
+
+    /// The specified address.
+    Fixed(VirtualAddress),
+}
+
+/// The `Backing` enum.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames, allocated on the first access.
+    Anonymous,
+
+    /// Pages mapped up front by the owner of the region.
+    Fixed,
+}
+
+/// The `VirtualRegion` type.
+///
+/// Represents a page-aligned range of virtual memory.
+#[derive(Debug, Copy, Clone)]
+pub struct VirtualRegion {
+    /// The start address.
+    pub start: VirtualAddress,
+
+    /// The size in bytes.
+    pub size: usize,
+
+    /// The flags of the pages.
+    pub flags: EntryFlags,
+
+    /// The backing kind.
+    pub backing: Backing,
+}
+
+/// The `VirtualRegion` implementation.
+impl VirtualRegion {
+    /// Constructs a new `VirtualRegion`.
+    pub fn new(start: VirtualAddress,
+               size: usize,
+               flags: EntryFlags,
+               backing: Backing)
+               -> VirtualRegion {
+        assert!(start.is_multiple_of(PAGE_SIZE) && size.is_multiple_of(PAGE_SIZE),
+                "Regions need to be page aligned");
+        assert!(size > 0);
+        VirtualRegion {
+            start,
+            size,
+            flags,
+            backing,
+        }
+    }
+
+    /// Gets the end address, exclusive.
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+
+    /// Tests if the region contains the specified address.
+    pub fn contains(&self, addr: VirtualAddress) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// The `AddressSpace` type.
+///
+/// Holds the virtual regions of an address space, sorted by address,
+/// and places new regions inside a window of the address space.
+///
+/// A sorted array stands in for a tree: lookups are binary searches,
+/// inserts move at most `MAX_VIRTUAL_REGIONS` entries, and nothing
+/// is allocated, which the memory controller can't do under its lock.
+/// Operations needing more regions fail without changing any.
+#[derive(Copy)]
+pub struct AddressSpace {
+    /// The regions.
+    regions: [VirtualRegion; MAX_VIRTUAL_REGIONS],
+
+    /// The number of regions.
+    count: usize,
+
+    /// The start of the placement window.
+    window_start: VirtualAddress,
+
+    /// The end of the placement window, exclusive.
+    window_end: VirtualAddress,
+}
+
+/// The `Clone` implementation for `AddressSpace`.
+impl Clone for AddressSpace {
+    /// Clones the address space.
+    fn clone(&self) -> AddressSpace {
+        *self
+    }
+}
+
+/// The `AddressSpace` implementation.
+impl AddressSpace {
+    /// Constructs a new empty `AddressSpace` placing
+    /// regions between the specified addresses.
+    pub const fn new(window_start: VirtualAddress, window_end: VirtualAddress) -> AddressSpace {
+        assert!(window_start.is_multiple_of(PAGE_SIZE) && window_end.is_multiple_of(PAGE_SIZE));
+        AddressSpace {
+            regions: [VirtualRegion {
+                start: 0,
+                size: 0,
+                flags: EntryFlags::empty(),
+                backing: Backing::Fixed,
+            }; MAX_VIRTUAL_REGIONS],
+            count: 0,
+            window_start,
+            window_end,
+        }
+    }
+
+    /// Adds a region.
+    pub fn add(&mut self, region: VirtualRegion) -> Result<(), VmaError> {
+        if self.overlapping(region.start, region.end()).is_some() {
+            return Err(VmaError::Overlap);
+        }
+        if self.count == MAX_VIRTUAL_REGIONS {
+            return Err(VmaError::TooManyRegions);
+        }
+        let index = self.index_after(region.start);
+        for i in (index..self.count).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+        self.regions[index] = region;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Removes the region starting at the specified address.
+    pub fn remove(&mut self, start: VirtualAddress) -> Option<VirtualRegion> {
+        let index = self.iter().position(|region| region.start == start)?;
+        let region = self.regions[index];
+        for i in index..self.count - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.count -= 1;
+        Some(region)
+    }
+
+    /// Finds the region containing the specified address.
+    pub fn find(&self, addr: VirtualAddress) -> Option<&VirtualRegion> {
+        let index = self.index_after(addr);
+        if index == 0 {
+            return None;
+        }
+        Some(&self.regions[index - 1]).filter(|region| region.contains(addr))
+    }
+
+    /// Gets a region overlapping the specified range.
+    pub fn overlapping(&self,
+                       start: VirtualAddress,
+                       end: VirtualAddress)
+                       -> Option<&VirtualRegion> {
+        self.iter().find(|region| start < region.end() && region.start < end)
+    }
+
+    /// Tests if the range is entirely covered by regions.
+    pub fn covers(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(region) => addr = region.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Finds a free range of the specified size in the placement window.
+    pub fn find_free(&self, size: usize, placement: Placement) -> Result<VirtualAddress, VmaError> {
+        self.find_free_in(self.window_start, self.window_end, size, placement)
+    }
+
+    /// Finds a free range of the specified size between the specified addresses.
+    pub fn find_free_in(&self,
+                        window_start: VirtualAddress,
+                        window_end: VirtualAddress,
+                        size: usize,
+                        placement: Placement)
+                        -> Result<VirtualAddress, VmaError> {
+        assert!(size > 0 && size.is_multiple_of(PAGE_SIZE));
+        match placement {
+            Placement::FirstFit => {
+                let mut start = window_start;
+                for region in self.iter() {
+                    if start + size <= region.start {
+                        break;
+                    }
+                    start = cmp::max(start, region.end());
+                }
+                if start + size <= window_end {
+                    Ok(start)
+                } else {
+                    Err(VmaError::OutOfSpace)
+                }
+            }
+            Placement::TopDown => {
+                let mut end = window_end;
+                for region in self.iter().rev() {
+                    if region.end() + size <= end {
+                        break;
+                    }
+                    end = cmp::min(end, region.start);
+                }
+                if window_start + size <= end {
+                    Ok(end - size)
+                } else {
+                    Err(VmaError::OutOfSpace)
+                }
+            }
+            Placement::Fixed(start) => {
+                if !start.is_multiple_of(PAGE_SIZE) {
+                    return Err(VmaError::Unaligned);
+                }
+                match self.overlapping(start, start + size) {
+                    Some(_) => Err(VmaError::Overlap),
+                    None => Ok(start),
+                }
+            }
+        }
+    }
+
+    /// Splits the region containing the specified address,
+    /// so that a region starts at the address.
+    pub fn split(&mut self, addr: VirtualAddress) -> Result<(), VmaError> {
+        assert!(addr.is_multiple_of(PAGE_SIZE));
+        let region = match self.find(addr) {
+            Some(region) if region.start != addr => *region,
+            _ => return Ok(()),
+        };
+        if self.count == MAX_VIRTUAL_REGIONS {
+            return Err(VmaError::TooManyRegions);
+        }
+        let index = self.index_after(addr) - 1;
+        self.regions[index].size = addr - region.start;
+        self.add(VirtualRegion { start: addr, size: region.end() - addr, ..region })
+    }
+
+    /// Splits the regions at both ends of the range,
+    /// so that the range consists of whole regions.
+    ///
+    /// Fails without changing the regions if the range is not page
+    /// aligned or not entirely covered by regions, or if the splits
+    /// would exceed `MAX_VIRTUAL_REGIONS`.
+    pub fn split_range(&mut self, start: VirtualAddress, end: VirtualAddress) -> Result<(), VmaError> {
+        if !start.is_multiple_of(PAGE_SIZE) || !end.is_multiple_of(PAGE_SIZE) {
+            return Err(VmaError::Unaligned);
+        }
+        if !self.covers(start, end) {
+            return Err(VmaError::NotReserved);
+        }
+        let splits = [start, end]
+            .iter()
+            .filter(|&&addr| self.find(addr).is_some_and(|region| region.start != addr))
+            .count();
+        if self.count + splits > MAX_VIRTUAL_REGIONS {
+            return Err(VmaError::TooManyRegions);
+        }
+        self.split(start)?;
+        self.split(end)
+    }
+
+    /// Tests if a fixed region overlaps the range.
+    pub fn overlaps_fixed(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        self.iter()
+            .any(|region| region.backing == Backing::Fixed && start < region.end() &&
+                          region.start < end)
+    }
+
+    /// Replaces the flags of every region inside the range.
+    pub fn set_flags(&mut self, start: VirtualAddress, end: VirtualAddress, flags: EntryFlags) {
+        for region in self.regions[..self.count].iter_mut() {
+            if start <= region.start && region.end() <= end {
+                region.flags = flags;
+            }
+        }
+    }
+
+    /// Gets an iterator over the regions.
+    pub fn iter(&self) -> slice::Iter<'_, VirtualRegion> {
+        self.regions[..self.count].iter()
+    }
+
+    /// Gets the index of the first region starting after the address.
+    fn index_after(&self, addr: VirtualAddress) -> usize {
+        match self.regions[..self.count].binary_search_by(|region| region.start.cmp(&addr)) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an address space with a window from 0x10000 up to 0x20000
+    /// holding anonymous regions between the specified addresses.
+    fn space(regions: &[(VirtualAddress, VirtualAddress)]) -> AddressSpace {
+        let mut space = AddressSpace::new(0x10000, 0x20000);
+        for &(start, end) in regions {
+            let region = VirtualRegion::new(start,
+                                            end - start,
+                                            EntryFlags::WRITABLE,
+                                            Backing::Anonymous);
+            space.add(region).unwrap();
+        }
+        space
+    }
+
+    /// Gets the ranges of the regions of an address space.
+    fn ranges(space: &AddressSpace) -> Vec<(VirtualAddress, VirtualAddress)> {
+        space.iter().map(|region| (region.start, region.end())).collect()
+    }
+
+    #[test]
+    fn add_keeps_regions_sorted_and_rejects_overlaps() {
+        let mut space = space(&[(0x14000, 0x15000), (0x11000, 0x12000)]);
+        assert_eq!(ranges(&space), [(0x11000, 0x12000), (0x14000, 0x15000)]);
+        let region = VirtualRegion::new(0x11000, 0x4000, EntryFlags::empty(), Backing::Fixed);
+        assert_eq!(space.add(region), Err(VmaError::Overlap));
+        assert_eq!(space.find(0x14fff).map(|region| region.start), Some(0x14000));
+        assert!(space.find(0x15000).is_none());
+    }
+
+    #[test]
+    fn find_free_first_fit_takes_the_lowest_gap() {
+        let space = space(&[(0x10000, 0x11000), (0x13000, 0x14000)]);
+        assert_eq!(space.find_free(0x2000, Placement::FirstFit), Ok(0x11000));
+        assert_eq!(space.find_free(0x3000, Placement::FirstFit), Ok(0x14000));
+        assert_eq!(space.find_free(0xd000, Placement::FirstFit), Err(VmaError::OutOfSpace));
+    }
+
+    #[test]
+    fn find_free_top_down_takes_the_highest_gap() {
+        let space = space(&[(0x1e000, 0x20000), (0x1b000, 0x1c000)]);
+        assert_eq!(space.find_free(0x2000, Placement::TopDown), Ok(0x1c000));
+        assert_eq!(space.find_free(0x3000, Placement::TopDown), Ok(0x18000));
+        assert_eq!(space.find_free(0xc000, Placement::TopDown), Err(VmaError::OutOfSpace));
+    }
+
+    #[test]
+    fn find_free_fixed_checks_alignment_and_overlaps() {
+        let space = space(&[(0x12000, 0x13000)]);
+        assert_eq!(space.find_free(0x1000, Placement::Fixed(0x11000)), Ok(0x11000));
+        assert_eq!(space.find_free(0x2000, Placement::Fixed(0x11000)), Err(VmaError::Overlap));
+        assert_eq!(space.find_free(0x1000, Placement::Fixed(0x11800)), Err(VmaError::Unaligned));
+    }
+
+    #[test]
+    fn covers_requires_contiguous_regions() {
+        let space = space(&[(0x11000, 0x12000), (0x12000, 0x14000), (0x15000, 0x16000)]);
+        assert!(space.covers(0x11000, 0x14000));
+        assert!(space.covers(0x11800, 0x13000));
+        assert!(!space.covers(0x11000, 0x16000));
+        assert!(!space.covers(0x10000, 0x12000));
+    }
+
+    #[test]
+    fn split_range_splits_both_ends() {
+        let mut space = space(&[(0x11000, 0x15000)]);
+        space.split_range(0x12000, 0x14000).unwrap();
+        assert_eq!(ranges(&space),
+                   [(0x11000, 0x12000), (0x12000, 0x14000), (0x14000, 0x15000)]);
+        assert!(space.iter().all(|region| region.flags == EntryFlags::WRITABLE));
+        space.split_range(0x12000, 0x14000).unwrap();
+        assert_eq!(space.iter().count(), 3);
+    }
+
+    #[test]
+    fn split_range_fails_without_changing_the_regions() {
+        let mut space = space(&[(0x11000, 0x15000)]);
+        assert_eq!(space.split_range(0x12000, 0x16000), Err(VmaError::NotReserved));
+        assert_eq!(space.split_range(0x12800, 0x14000), Err(VmaError::Unaligned));
+        assert_eq!(ranges(&space), [(0x11000, 0x15000)]);
+
+        let mut space = AddressSpace::new(0x0, MAX_VIRTUAL_REGIONS * 0x2000);
+        for index in 0..MAX_VIRTUAL_REGIONS - 1 {
+            let region = VirtualRegion::new(index * 0x2000,
+                                            0x2000,
+                                            EntryFlags::empty(),
+                                            Backing::Anonymous);
+            space.add(region).unwrap();
+        }
+        assert_eq!(space.split_range(0x1000, 0x3000), Err(VmaError::TooManyRegions));
+        assert_eq!(space.iter().count(), MAX_VIRTUAL_REGIONS - 1);
+        space.split_range(0x1000, 0x2000).unwrap();
+        assert_eq!(space.iter().count(), MAX_VIRTUAL_REGIONS);
+    }
+}
@@ -0,0 +1,463 @@
+use core::{cmp, mem, slice};
+use memory::{PAGE_SIZE, Frame, FrameAllocator, FrameStats, MemoryMap, RegionKind};
+use memory::{phys_to_virt, virt_to_phys};
+use memory::paging::PhysicalAddress;
+
+/// The largest order.
+///
+/// Blocks of this order span 1 GiB, the largest page size.
+pub const MAX_ORDER: usize = 18;
+
+/// The number of orders.
+const ORDER_COUNT: usize = MAX_ORDER + 1;
+
+/// The number of blocks tracked by a bitmap word.
+const BITS_PER_WORD: usize = 64;
+
+/// The frame index ending a free list.
+const NO_BLOCK: u32 = u32::MAX;
+
+/// The number of free blocks listed per order by `dump_free_lists`.
+const DUMP_LIMIT: usize = 8;
+
+/// The `BuddyFrameAllocator` type.
+///
+/// Hands out blocks of `2^order` frames, aligned to their size.
+/// Free blocks are kept in one list per order, with a bitmap per order
+/// to find free buddies. The links live in a table indexed by frame
+/// instead of the free blocks themselves, so memory outside the
+/// physical memory mapped at boot can be managed without touching it.
+pub struct BuddyFrameAllocator {
+    /// The free list links of all frames.
+    ///
+    /// Only the links of the first frame of a free block are used.
+    links: &'static mut [Link],
+
+    /// The first free block of each order, as a frame index.
+    heads: [u32; ORDER_COUNT],
+
+    /// The bitmaps of all orders.
+    ///
+    /// A set bit marks a free block.
+    bitmaps: &'static mut [u64],
+
+    /// The offset of the bitmap of each order, in words.
+    offsets: [usize; ORDER_COUNT],
+
+    /// The number of blocks of each order.
+    block_counts: [usize; ORDER_COUNT],
+
+    /// The number of free blocks of each order.
+    free_blocks: [usize; ORDER_COUNT],
+
+    /// The statistics.
+    stats: FrameStats,
+}
+
+/// The `Link` type.
+///
+/// Links a free block into the free list of its order.
+#[derive(Copy, Clone)]
+struct Link {
+    /// The frame index of the next free block.
+    next: u32,
+
+    /// The frame index of the previous free block.
+    prev: u32,
+}
+
+/// The `FrameAllocator` implementation for `BuddyFrameAllocator`.
+impl FrameAllocator for BuddyFrameAllocator {
+    /// Allocates a frame.
+    fn alloc_frame(&mut self) -> Option<Frame> {
+        self.alloc_frames(0)
+    }
+
+    /// Deallocates a frame.
+    fn dealloc_frame(&mut self, frame: Frame) {
+        self.dealloc_frames(frame, 0);
+    }
+
+    /// Allocates `2^order` contiguous frames.
+    ///
+    /// Splits the smallest larger block if no block
+    /// of the requested order is free.
+    fn alloc_frames(&mut self, order: usize) -> Option<Frame> {
+        assert!(order <= MAX_ORDER);
+        for current in order..ORDER_COUNT {
+            if let Some(block) = self.take_free(current) {
+                let index = block << current;
+
+                // Hand the upper halves back, one order at a time
+                for split in (order..current).rev() {
+                    self.set_free(split, (index >> split) + 1);
+                }
+                self.stats.free -= 1 << order;
+                return Some(Frame { index });
+            }
+        }
+        None
+    }
+
+    /// Deallocates `2^order` contiguous frames.
+    ///
+    /// Merges the block with its buddy as long as the buddy is free.
+    fn dealloc_frames(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER);
+        assert!(frame.index.is_multiple_of(1 << order),
+                "{:?} is not aligned to order {}",
+                frame,
+                order);
+        self.release(frame.index, order);
+        self.stats.free += 1 << order;
+    }
+}
+
+/// The `BuddyFrameAllocator` implementation.
+impl BuddyFrameAllocator {
+    /// Constructs a new `BuddyFrameAllocator` managing no memory.
+    pub const fn empty() -> BuddyFrameAllocator {
+        BuddyFrameAllocator {
+            links: &mut [],
+            heads: [NO_BLOCK; ORDER_COUNT],
+            bitmaps: &mut [],
+            offsets: [0; ORDER_COUNT],
+            block_counts: [0; ORDER_COUNT],
+            free_blocks: [0; ORDER_COUNT],
+            stats: FrameStats {
+                total: 0,
+                free: 0,
+                reserved: 0,
+            },
+        }
+    }
+
+    /// Constructs a new `BuddyFrameAllocator`.
+    ///
+    /// Places the links and the bitmaps in the first usable region
+    /// with enough room and reserves them in the memory map.
+    pub fn new(map: &mut MemoryMap) -> BuddyFrameAllocator {
+        // Get the number of frames below the end of RAM
+        let frame_count = Frame::get_frame_for_address(map.ram_end() - 1).index + 1;
+        assert!(frame_count < NO_BLOCK as usize, "Too much memory for the buddy allocator");
+
+        // Lay the bitmaps out back to back
+        let mut offsets = [0; ORDER_COUNT];
+        let mut block_counts = [0; ORDER_COUNT];
+        let mut words = 0;
+        for order in 0..ORDER_COUNT {
+            offsets[order] = words;
+            block_counts[order] = frame_count >> order;
+            words += block_counts[order].div_ceil(BITS_PER_WORD);
+        }
+        let links_size = frame_count * mem::size_of::<Link>();
+        let metadata_size = links_size + words * 8;
+
+        // Find a place for the links followed by the bitmaps
+        let start = map.find_gap(metadata_size).expect("No room for the buddy metadata");
+        map.reserve(start, start + metadata_size, "Buddy metadata")
+            .expect("Failed to reserve the buddy metadata");
+        let links = unsafe {
+            slice::from_raw_parts_mut(phys_to_virt(start) as *mut Link, frame_count)
+        };
+        let bitmaps = unsafe {
+            slice::from_raw_parts_mut(phys_to_virt(start + links_size) as *mut u64, words)
+        };
+        for word in bitmaps.iter_mut() {
+            *word = 0;
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            links,
+            heads: [NO_BLOCK; ORDER_COUNT],
+            bitmaps,
+            offsets,
+            block_counts,
+            free_blocks: [0; ORDER_COUNT],
+            stats: FrameStats {
+                total: 0,
+                free: 0,
+                reserved: 0,
+            },
+        };
+
+        // Free the usable frames in the largest blocks that fit
+        for region in map.iter().filter(|region| region.kind.is_ram()) {
+            allocator.stats.total += region.frame_count();
+            if region.kind != RegionKind::Usable {
+                allocator.stats.reserved += region.frame_count();
+                continue;
+            }
+            allocator.release_run(region.first_frame(), region.end_frame());
+            allocator.stats.free += region.frame_count();
+        }
+        allocator
+    }
+
+    /// Allocates a contiguous run of frames.
+    ///
+    /// The index of the first frame is a multiple of `align`,
+    /// which is given in frames and has to be a power of two.
+    /// The frames past the run in the block holding it are freed again.
+    #[allow(dead_code)]
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        assert!(count > 0 && align.is_power_of_two());
+        let order = cmp::max(count.next_power_of_two(), align).trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+        let frame = self.alloc_frames(order)?;
+        self.release_run(frame.index + count, frame.index + (1 << order));
+        self.stats.free += (1 << order) - count;
+        Some(frame)
+    }
+
+    /// Deallocates a contiguous run of frames
+    /// allocated with `alloc_contiguous`.
+    #[allow(dead_code)]
+    pub fn dealloc_contiguous(&mut self, frame: Frame, count: usize) {
+        self.release_run(frame.index, frame.index + count);
+        self.stats.free += count;
+    }
+
+    /// Gets the physical region occupied by the links and the bitmaps.
+    pub fn metadata_region(&self) -> (PhysicalAddress, PhysicalAddress) {
+        let start = virt_to_phys(self.links.as_ptr() as usize);
+        let end = virt_to_phys(self.bitmaps.as_ptr() as usize) + self.bitmaps.len() * 8;
+        (start, end)
+    }
+
+    /// Gets the statistics.
+    #[allow(dead_code)]
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Prints the free blocks of every order.
+    #[allow(dead_code)]
+    pub fn dump_free_lists(&self) {
+        for order in 0..ORDER_COUNT {
+            print!("Order {:>2}: {} free", order, self.free_blocks[order]);
+            let mut index = self.heads[order];
+            for _ in 0..DUMP_LIMIT {
+                if index == NO_BLOCK {
+                    break;
+                }
+                print!(" 0x{:x}", index as usize * PAGE_SIZE);
+                index = self.links[index as usize].next;
+            }
+            if index != NO_BLOCK {
+                print!(" ...");
+            }
+            println!("");
+        }
+    }
+
+    /// Inserts the frames from `start` up to `end`
+    /// in the largest aligned blocks that fit.
+    fn release_run(&mut self, start: usize, end: usize) {
+        let mut index = start;
+        while index < end {
+            let mut order = MAX_ORDER;
+            while !index.is_multiple_of(1 << order) || index + (1 << order) > end {
+                order -= 1;
+            }
+            self.release(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Inserts a free block, merging it with its buddy
+    /// as long as the buddy is free.
+    fn release(&mut self, index: usize, order: usize) {
+        // The block may have been merged into a larger free block
+        assert!((order..ORDER_COUNT).all(|larger| !self.is_free(larger, index >> larger)),
+                "Double free of {:?}",
+                Frame { index });
+        let mut index = index;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = (index >> order) ^ 1;
+            if !self.is_free(order, buddy) {
+                break;
+            }
+            self.clear_free(order, buddy);
+            index &= !(1 << order);
+            order += 1;
+        }
+        self.set_free(order, index >> order);
+    }
+
+    /// Takes the first free block of the specified order.
+    ///
+    /// Returns the block number.
+    fn take_free(&mut self, order: usize) -> Option<usize> {
+        let head = self.heads[order];
+        if head == NO_BLOCK {
+            return None;
+        }
+        let block = head as usize >> order;
+        self.clear_free(order, block);
+        Some(block)
+    }
+
+    /// Tests if a block is free.
+    fn is_free(&self, order: usize, block: usize) -> bool {
+        if block >= self.block_counts[order] {
+            return false;
+        }
+        let word = self.offsets[order] + block / BITS_PER_WORD;
+        self.bitmaps[word] & (1 << (block % BITS_PER_WORD)) != 0
+    }
+
+    /// Marks a block as free and pushes it onto the free list of its order.
+    fn set_free(&mut self, order: usize, block: usize) {
+        let word = self.offsets[order] + block / BITS_PER_WORD;
+        self.bitmaps[word] |= 1 << (block % BITS_PER_WORD);
+        self.free_blocks[order] += 1;
+
+        let index = (block << order) as u32;
+        let head = self.heads[order];
+        self.links[index as usize] = Link {
+            next: head,
+            prev: NO_BLOCK,
+        };
+        if head != NO_BLOCK {
+            self.links[head as usize].prev = index;
+        }
+        self.heads[order] = index;
+    }
+
+    /// Marks a free block as taken and unlinks it from its free list.
+    fn clear_free(&mut self, order: usize, block: usize) {
+        let word = self.offsets[order] + block / BITS_PER_WORD;
+        self.bitmaps[word] &= !(1 << (block % BITS_PER_WORD));
+        self.free_blocks[order] -= 1;
+
+        let link = self.links[block << order];
+        if link.prev == NO_BLOCK {
+            self.heads[order] = link.next;
+        } else {
+            self.links[link.prev as usize].next = link.next;
+        }
+        if link.next != NO_BLOCK {
+            self.links[link.next as usize].prev = link.prev;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an allocator over `frame_count` frames with
+    /// the frames from `start` up to `end` free.
+    fn allocator(frame_count: usize, start: usize, end: usize) -> BuddyFrameAllocator {
+        let mut allocator = BuddyFrameAllocator::empty();
+        let mut words = 0;
+        for order in 0..ORDER_COUNT {
+            allocator.offsets[order] = words;
+            allocator.block_counts[order] = frame_count >> order;
+            words += allocator.block_counts[order].div_ceil(BITS_PER_WORD);
+        }
+        let link = Link {
+            next: NO_BLOCK,
+            prev: NO_BLOCK,
+        };
+        allocator.links = Box::leak(vec![link; frame_count].into_boxed_slice());
+        allocator.bitmaps = Box::leak(vec![0; words].into_boxed_slice());
+        allocator.release_run(start, end);
+        allocator.stats.total = frame_count;
+        allocator.stats.free = end - start;
+        allocator
+    }
+
+    /// Gets the number of free blocks of the orders up to 4.
+    fn free_blocks(allocator: &BuddyFrameAllocator) -> [usize; 5] {
+        let mut free_blocks = [0; 5];
+        free_blocks.copy_from_slice(&allocator.free_blocks[..5]);
+        free_blocks
+    }
+
+    #[test]
+    fn release_run_uses_the_largest_aligned_blocks() {
+        let allocator = allocator(16, 1, 14);
+        assert_eq!(free_blocks(&allocator), [1, 2, 2, 0, 0]);
+        assert!(allocator.is_free(0, 1));
+        assert!(allocator.is_free(1, 1));
+        assert!(allocator.is_free(2, 1));
+        assert!(allocator.is_free(2, 2));
+        assert!(allocator.is_free(1, 6));
+    }
+
+    #[test]
+    fn alloc_splits_larger_blocks() {
+        let mut allocator = allocator(16, 0, 16);
+        assert_eq!(free_blocks(&allocator), [0, 0, 0, 0, 1]);
+        assert_eq!(allocator.alloc_frame(), Some(Frame { index: 0 }));
+        assert_eq!(free_blocks(&allocator), [1, 1, 1, 1, 0]);
+        assert_eq!(allocator.alloc_frames(1), Some(Frame { index: 2 }));
+        assert_eq!(allocator.alloc_frame(), Some(Frame { index: 1 }));
+        assert_eq!(free_blocks(&allocator), [0, 0, 1, 1, 0]);
+        assert_eq!(allocator.stats().free, 12);
+    }
+
+    #[test]
+    fn alloc_fails_when_no_block_is_large_enough() {
+        let mut allocator = allocator(16, 0, 12);
+        assert_eq!(allocator.alloc_frames(4), None);
+        assert_eq!(allocator.alloc_frames(3), Some(Frame { index: 0 }));
+        assert_eq!(allocator.alloc_frames(3), None);
+    }
+
+    #[test]
+    fn dealloc_coalesces_free_buddies() {
+        let mut allocator = allocator(16, 0, 16);
+        let first = allocator.alloc_frame().unwrap();
+        let second = allocator.alloc_frame().unwrap();
+        allocator.dealloc_frame(first);
+        assert_eq!(free_blocks(&allocator), [1, 1, 1, 1, 0]);
+        allocator.dealloc_frame(second);
+        assert_eq!(free_blocks(&allocator), [0, 0, 0, 0, 1]);
+        assert_eq!(allocator.stats().free, 16);
+    }
+
+    #[test]
+    fn dealloc_keeps_blocks_with_a_taken_buddy_apart() {
+        let mut allocator = allocator(16, 0, 16);
+        let block = allocator.alloc_frames(2).unwrap();
+        let buddy = allocator.alloc_frames(2).unwrap();
+        assert_eq!(buddy.index ^ block.index, 4);
+        allocator.dealloc_frames(block, 2);
+        assert_eq!(free_blocks(&allocator), [0, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Double free")]
+    fn dealloc_panics_on_double_free() {
+        let mut allocator = allocator(16, 0, 16);
+        let index = allocator.alloc_frame().unwrap().index;
+        allocator.dealloc_frame(Frame { index });
+        allocator.dealloc_frame(Frame { index });
+    }
+
+    #[test]
+    fn alloc_contiguous_frees_the_tail_of_the_block() {
+        let mut allocator = allocator(16, 0, 16);
+        let frame = allocator.alloc_contiguous(3, 1).unwrap();
+        assert_eq!(frame.index, 0);
+        assert!(allocator.is_free(0, 3));
+        assert_eq!(allocator.stats().free, 13);
+        allocator.dealloc_contiguous(frame, 3);
+        assert_eq!(free_blocks(&allocator), [0, 0, 0, 0, 1]);
+        assert_eq!(allocator.stats().free, 16);
+    }
+
+    #[test]
+    fn alloc_contiguous_honours_the_alignment() {
+        let mut allocator = allocator(16, 0, 16);
+        allocator.alloc_frame().unwrap();
+        let frame = allocator.alloc_contiguous(1, 8).unwrap();
+        assert_eq!(frame.index, 8);
+    }
+}
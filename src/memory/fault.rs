@@ -0,0 +1,155 @@
+use core::fmt;
+use core::ptr;
+use memory::{self, PAGE_SIZE, FrameAllocator, MemoryController, MEMORY_CONTROLLER};
+use memory::address_space::Backing;
+use memory::paging::{Page, VirtualAddress, EntryFlags};
+
+/// The page used to fill the copy of a copy-on-write page.
+///
+/// Located in the first GiB of the kernel windows.
+const COPY_PAGE: VirtualAddress = memory::KERNEL_WINDOWS + PAGE_SIZE;
+
+// The page fault error code bits
+bitflags! {
+    pub struct PageFaultCode: u64 {
+        const PROTECTION_VIOLATION = 1 << 0;
+        const CAUSED_BY_WRITE =      1 << 1;
+        const USER_MODE =            1 << 2;
+        const MALFORMED_TABLE =      1 << 3;
+        const INSTRUCTION_FETCH =    1 << 4;
+    }
+}
+
+/// The `fmt::Display` implementation for `PageFaultCode`.
+impl fmt::Display for PageFaultCode {
+    /// Formats the error code as a sentence.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.contains(PageFaultCode::INSTRUCTION_FETCH) {
+            "Instruction fetch from"
+        } else if self.contains(PageFaultCode::CAUSED_BY_WRITE) {
+            "Write to"
+        } else {
+            "Read from"
+        };
+        let page = if self.contains(PageFaultCode::PROTECTION_VIOLATION) {
+            "a present"
+        } else {
+            "a non-present"
+        };
+        let mode = if self.contains(PageFaultCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {} page in {} mode", access, page, mode)?;
+        if self.contains(PageFaultCode::MALFORMED_TABLE) {
+            write!(f, " (reserved bit set)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Tries to resolve a page fault.
+///
+/// Retries accesses the page permits by now, which failed on a stale
+/// TLB entry, as the CPU drops the entry when raising the fault.
+/// Copies copy-on-write pages on write, and maps a zeroed frame
+/// if the address lies inside an anonymous region of the active
+/// page table or the shared higher half that permits the access.
+/// Returns `false` for genuine faults, which includes faults
+/// raised while the memory controller is locked.
+pub fn handle_page_fault(addr: VirtualAddress, code: PageFaultCode) -> bool {
+    let mut controller = match MEMORY_CONTROLLER.try_lock() {
+        Some(controller) => controller,
+        None => return false,
+    };
+    if !controller.initialized {
+        return false;
+    }
+    let mc = &mut *controller;
+    if code.contains(PageFaultCode::MALFORMED_TABLE) {
+        return false;
+    }
+    if code.contains(PageFaultCode::PROTECTION_VIOLATION) {
+        match mc.active_table.page_flags(Page::get_page_at_address(addr)) {
+            Some(flags) if permits(flags, code) => return true,
+            _ => (),
+        }
+        if code.contains(PageFaultCode::CAUSED_BY_WRITE) &&
+           !code.contains(PageFaultCode::INSTRUCTION_FETCH) {
+            return copy_on_write(mc, Page::get_page_at_address(addr));
+        }
+        return false;
+    }
+    map_anonymous(mc, addr, code)
+}
+
+/// Gives a copy-on-write page a writable frame of its own.
+///
+/// The last owner of a shared frame keeps it,
+/// every other owner gets a copy.
+fn copy_on_write(mc: &mut MemoryController, page: Page) -> bool {
+    let mut flags = match mc.active_table.page_flags(page) {
+        Some(flags) if flags.contains(EntryFlags::COPY_ON_WRITE) => flags,
+        _ => return false,
+    };
+
+    // Test if the region still permits writes
+    match mc.find_region(page.address()) {
+        Some(region) if !region.flags.contains(EntryFlags::WRITABLE) => return false,
+        _ => (),
+    }
+    flags.remove(EntryFlags::COPY_ON_WRITE);
+    flags.insert(EntryFlags::WRITABLE);
+    let frame = mc.active_table.translate_page(page).unwrap();
+    if mc.frame_refs.count(&frame) == 1 {
+        return mc.active_table.update_flags(Page::range(page, page.next()), flags).is_ok();
+    }
+
+    // Copy the shared frame into a new one
+    let copy = match mc.frame_allocator.alloc_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    let copy_page = Page::get_page_at_address(COPY_PAGE);
+    if mc.active_table
+        .map_to(copy_page,
+                copy.clone(),
+                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                &mut mc.frame_allocator)
+        .is_err() {
+        mc.frame_allocator.dealloc_frame(copy);
+        return false;
+    }
+    unsafe {
+        ptr::copy_nonoverlapping(page.address() as *const u8,
+                                 copy_page.address() as *mut u8,
+                                 PAGE_SIZE);
+    }
+    mc.active_table.unmap_keep_frame(copy_page, &mut mc.frame_allocator);
+
+    // Replace the shared frame with the copy
+    let shared = mc.active_table.unmap_keep_frame(page, &mut mc.frame_allocator);
+    mc.frame_refs.release(&shared);
+    mc.active_table.map_to(page, copy, flags, &mut mc.frame_allocator).is_ok()
+}
+
+/// Maps a zeroed frame for a non-present page of an anonymous region.
+fn map_anonymous(mc: &mut MemoryController, addr: VirtualAddress, code: PageFaultCode) -> bool {
+    let region = match mc.find_region(addr) {
+        Some(region) if region.backing == Backing::Anonymous => *region,
+        _ => return false,
+    };
+
+    if !permits(region.flags, code) {
+        return false;
+    }
+    mc.map_zeroed(Page::get_page_at_address(addr), region.flags).is_ok()
+}
+
+/// Tests if the flags permit the access.
+fn permits(flags: EntryFlags, code: PageFaultCode) -> bool {
+    !(code.contains(PageFaultCode::CAUSED_BY_WRITE) && !flags.contains(EntryFlags::WRITABLE)) &&
+    !(code.contains(PageFaultCode::INSTRUCTION_FETCH) && flags.contains(EntryFlags::NO_EXECUTE)) &&
+    !(code.contains(PageFaultCode::USER_MODE) && !flags.contains(EntryFlags::USER_ACCESSIBLE))
+}
@@ -0,0 +1,394 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem::align_of;
+use core::ptr;
+use spin::Mutex;
+use interrupts;
+use memory::{self, PAGE_SIZE};
+use memory::slab;
+use memory::paging::{Page, EntryFlags};
+
+/// The start address of the kernel heap.
+///
+/// Located right after the first GiB of the kernel windows.
+pub const HEAP_START: usize = memory::KERNEL_WINDOWS + 0x4000_0000;
+
+/// The size of the virtual range reserved for the kernel heap.
+///
+/// Only the pages that hold allocations or hole headers
+/// are actually mapped.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+
+/// The kernel heap allocator.
+#[cfg_attr(not(test), global_allocator)]
+static HEAP_ALLOCATOR: HeapAllocator = HeapAllocator::new();
+
+/// Hands the reserved heap range to the heap allocator.
+///
+/// Pages are mapped through the memory controller as the
+/// heap grows, so nothing may allocate while holding it.
+/// Doing so panics, see `memory::with_controller`.
+pub fn init() {
+    interrupts::without_interrupts(|| unsafe {
+        HEAP_ALLOCATOR.holes.lock().init(HEAP_START, HEAP_MAX_SIZE);
+    });
+}
+
+/// Gets the heap statistics.
+#[allow(dead_code)]
+pub fn stats() -> HeapStats {
+    interrupts::without_interrupts(|| HEAP_ALLOCATOR.holes.lock().stats)
+}
+
+/// Aligns the address upwards.
+///
+/// The alignment must be a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Aligns the address downwards.
+///
+/// The alignment must be a power of two.
+fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+/// The `HeapStats` type.
+#[derive(Debug, Copy, Clone)]
+pub struct HeapStats {
+    /// The number of bytes in use.
+    pub bytes_in_use: usize,
+
+    /// The highest number of bytes ever in use.
+    pub peak_bytes_in_use: usize,
+
+    /// The number of pages currently mapped.
+    pub pages_mapped: usize,
+}
+
+/// The `HeapStats` implementation.
+impl HeapStats {
+    /// Constructs a new empty `HeapStats`.
+    const fn new() -> HeapStats {
+        HeapStats {
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+            pages_mapped: 0,
+        }
+    }
+}
+
+/// The `Hole` type.
+///
+/// Represents a free block of heap memory.
+/// Stored at the start of the block it describes.
+struct Hole {
+    /// The size of the block, including the hole itself.
+    size: usize,
+
+    /// The next hole, sorted by address.
+    next: *mut Hole,
+}
+
+/// The smallest block that can hold a `Hole`.
+const MIN_BLOCK_SIZE: usize = 16;
+
+/// The `HoleList` type.
+///
+/// Represents a first-fit, address-ordered free list.
+///
+/// The page holding the header of each hole is always mapped,
+/// while the pages that only hold free memory are not.
+pub struct HoleList {
+    /// The dummy head of the list.
+    head: Hole,
+
+    /// The statistics.
+    stats: HeapStats,
+}
+
+/// The `Send` implementation for `HoleList`.
+///
+/// The raw pointers only ever point into the kernel heap.
+unsafe impl Send for HoleList {}
+
+/// The `HoleList` implementation.
+impl HoleList {
+    /// Constructs a new empty `HoleList`.
+    pub const fn empty() -> HoleList {
+        HoleList {
+            head: Hole {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+            stats: HeapStats::new(),
+        }
+    }
+
+    /// Initializes the list with a single hole
+    /// spanning the specified region.
+    ///
+    /// # Safety
+    ///
+    /// The region must be unused.
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        assert!(start.is_multiple_of(align_of::<Hole>()));
+        assert!(size >= MIN_BLOCK_SIZE);
+        assert!(self.map_range(start, start + MIN_BLOCK_SIZE),
+                "Failed to map the heap");
+        let hole = start as *mut Hole;
+        ptr::write(hole,
+                   Hole {
+                       size,
+                       next: ptr::null_mut(),
+                   });
+        self.head.next = hole;
+    }
+
+    /// Maps every unmapped page overlapping the range
+    /// as writable, non-executable data.
+    ///
+    /// Returns `false` if a page could not be mapped, after unmapping
+    /// the pages this call mapped. Only the first and the last page
+    /// can already hold other blocks or hole headers, so the pages
+    /// this call maps form a single run.
+    fn map_range(&mut self, start: usize, end: usize) -> bool {
+        let stats = &mut self.stats;
+        memory::with_controller_for_alloc(|mc| {
+            let mut first_mapped = None;
+            for page in Page::range_containing(start, end) {
+                if mc.active_table.translate_page(page).is_some() {
+                    continue;
+                }
+                let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+                if mc.active_table.map(page, flags, &mut mc.frame_allocator).is_err() {
+                    if let Some(first) = first_mapped {
+                        let pages = Page::range(first, page);
+                        stats.pages_mapped -= pages.len();
+                        mc.active_table.unmap_range(pages, &mut mc.frame_allocator);
+                    }
+                    return false;
+                }
+                first_mapped = first_mapped.or(Some(page));
+                stats.pages_mapped += 1;
+            }
+            true
+        })
+    }
+
+    /// Unmaps every mapped page entirely inside the range
+    /// and returns its frame to the frame allocator.
+    fn unmap_range(&mut self, start: usize, end: usize) {
+        let stats = &mut self.stats;
+        let pages = Page::range(Page::get_page_at_address(align_up(start, PAGE_SIZE)),
+                                Page::get_page_at_address(align_down(end, PAGE_SIZE)));
+        memory::with_controller_for_alloc(|mc| {
+            for page in pages {
+                if mc.active_table.translate_page(page).is_some() {
+                    mc.active_table.unmap(page, &mut mc.frame_allocator);
+                    stats.pages_mapped -= 1;
+                }
+            }
+        });
+    }
+
+    /// Gets the size and alignment of the block
+    /// used for the specified layout.
+    fn block_size_align(layout: &Layout) -> (usize, usize) {
+        let size = align_up(layout.size(), align_of::<Hole>());
+        let size = if size < MIN_BLOCK_SIZE {
+            MIN_BLOCK_SIZE
+        } else {
+            size
+        };
+        let align = if layout.align() < align_of::<Hole>() {
+            align_of::<Hole>()
+        } else {
+            layout.align()
+        };
+        (size, align)
+    }
+
+    /// Allocates a block using the first hole that fits.
+    ///
+    /// Returns a null pointer if no hole fits.
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = HoleList::block_size_align(&layout);
+        let mut prev: *mut Hole = &mut self.head;
+        unsafe {
+            while !(*prev).next.is_null() {
+                let hole = (*prev).next;
+                let hole_start = hole as usize;
+                let hole_end = hole_start + (*hole).size;
+
+                // Leave room for a hole in front of the block if it is misaligned
+                let mut alloc_start = align_up(hole_start, align);
+                if alloc_start != hole_start && alloc_start - hole_start < MIN_BLOCK_SIZE {
+                    alloc_start = align_up(hole_start + MIN_BLOCK_SIZE, align);
+                }
+                let alloc_end = alloc_start + size;
+
+                // Test if the block fits and the rest can become a hole
+                let back = hole_end.saturating_sub(alloc_end);
+                if alloc_end > hole_end || (back > 0 && back < MIN_BLOCK_SIZE) {
+                    prev = hole;
+                    continue;
+                }
+
+                // Map the block and the header of the hole behind it
+                let mapped = if back > 0 {
+                    self.map_range(alloc_start, alloc_end + MIN_BLOCK_SIZE)
+                } else {
+                    self.map_range(alloc_start, alloc_end)
+                };
+                if !mapped {
+                    return ptr::null_mut();
+                }
+
+                // Replace the hole with the padding around the block
+                let mut next = (*hole).next;
+                if back > 0 {
+                    let back_hole = alloc_end as *mut Hole;
+                    ptr::write(back_hole,
+                               Hole {
+                                   size: back,
+                                   next,
+                               });
+                    next = back_hole;
+                }
+                if alloc_start > hole_start {
+                    (*hole).size = alloc_start - hole_start;
+                    (*hole).next = next;
+                } else {
+                    (*prev).next = next;
+                }
+
+                self.stats.bytes_in_use += size;
+                if self.stats.bytes_in_use > self.stats.peak_bytes_in_use {
+                    self.stats.peak_bytes_in_use = self.stats.bytes_in_use;
+                }
+                return alloc_start as *mut u8;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// Frees a block allocated with the specified layout.
+    ///
+    /// # Safety
+    ///
+    /// The block must have been allocated from this list
+    /// with the same layout.
+    pub unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = HoleList::block_size_align(&layout);
+        self.stats.bytes_in_use -= size;
+        self.deallocate(ptr, size);
+    }
+
+    /// Inserts a block into the list, merging it
+    /// with adjacent holes.
+    ///
+    /// Unmaps the pages that end up entirely free.
+    ///
+    /// # Safety
+    ///
+    /// The block must be unused and lie inside the heap.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize) {
+        let addr = ptr as usize;
+
+        // Find the last hole in front of the block
+        let mut prev: *mut Hole = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+        let next = (*prev).next;
+
+        // Insert the block, merging it with the following hole
+        let hole = addr as *mut Hole;
+        let merged_next = !next.is_null() && addr + size == next as usize;
+        if merged_next {
+            ptr::write(hole,
+                       Hole {
+                           size: size + (*next).size,
+                           next: (*next).next,
+                       });
+        } else {
+            ptr::write(hole,
+                       Hole {
+                           size,
+                           next,
+                       });
+        }
+        (*prev).next = hole;
+
+        // Merge the block with the preceding hole
+        let mut merged = hole;
+        if !core::ptr::eq(prev, &self.head) && prev as usize + (*prev).size == addr {
+            (*prev).size += (*hole).size;
+            (*prev).next = (*hole).next;
+            merged = prev;
+        }
+
+        // Release the pages the block and the merged header touched,
+        // as far as they lie behind the header of the merged hole
+        let freed_end = if merged_next {
+            addr + size + MIN_BLOCK_SIZE
+        } else {
+            addr + size
+        };
+        let hole_start = merged as usize + MIN_BLOCK_SIZE;
+        let hole_end = merged as usize + (*merged).size;
+        let start = cmp::max(align_down(addr, PAGE_SIZE), hole_start);
+        let end = cmp::min(align_up(freed_end, PAGE_SIZE), hole_end);
+        if start < end {
+            self.unmap_range(start, end);
+        }
+    }
+}
+
+/// The `HeapAllocator` type.
+pub struct HeapAllocator {
+    /// The free list.
+    holes: Mutex<HoleList>,
+}
+
+/// The `HeapAllocator` implementation.
+impl HeapAllocator {
+    /// Constructs a new empty `HeapAllocator`.
+    pub const fn new() -> HeapAllocator {
+        HeapAllocator { holes: Mutex::new(HoleList::empty()) }
+    }
+}
+
+/// The `Default` implementation for `HeapAllocator`.
+impl Default for HeapAllocator {
+    fn default() -> HeapAllocator {
+        HeapAllocator::new()
+    }
+}
+
+/// The `GlobalAlloc` implementation for `HeapAllocator`.
+///
+/// Small blocks come from the slab size classes, everything
+/// else from the free list. Interrupts stay disabled while either
+/// is locked. Growing and shrinking them takes the memory controller,
+/// though, so an interrupt handler that allocates or frees while
+/// the interrupted code holds the controller panics.
+unsafe impl GlobalAlloc for HeapAllocator {
+    /// Allocates a block.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match slab::size_class(&layout) {
+            Some(class) => slab::allocate(class),
+            None => interrupts::without_interrupts(|| self.holes.lock().allocate_first_fit(layout)),
+        }
+    }
+
+    /// Deallocates a block.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match slab::size_class(&layout) {
+            Some(class) => slab::deallocate(class, ptr),
+            None => interrupts::without_interrupts(|| self.holes.lock().free(ptr, layout)),
+        }
+    }
+}
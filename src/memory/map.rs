@@ -0,0 +1,408 @@
+use core::fmt;
+use core::iter::{Filter, once};
+use core::slice;
+use memory::{PAGE_SIZE, PHYS_MAP_END, ReservedRegions, ReservedError};
+use memory::paging::PhysicalAddress;
+
+/// The maximum number of memory regions.
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+/// The multiboot2 end tag type.
+const TAG_END: u32 = 0;
+
+/// The multiboot2 memory map tag type.
+const TAG_MEMORY_MAP: u32 = 6;
+
+/// An empty region, used to fill unused slots.
+const EMPTY_REGION: MemoryRegion = MemoryRegion {
+    start: 0,
+    end: 0,
+    kind: RegionKind::Reserved,
+};
+
+/// The `RegionKind` enum.
+///
+/// Ordered by precedence: where firmware regions overlap,
+/// the region with the greater kind wins.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    /// Free memory.
+    Usable,
+
+    /// Usable memory in use since boot.
+    InUse,
+
+    /// ACPI tables, usable once parsed.
+    AcpiReclaimable,
+
+    /// ACPI non-volatile storage.
+    AcpiNvs,
+
+    /// Memory reserved by the firmware.
+    Reserved,
+
+    /// Defective memory.
+    BadMemory,
+}
+
+/// The `RegionKind` implementation.
+impl RegionKind {
+    /// Gets the region kind for a multiboot2 area type.
+    fn from_area_type(typ: u32) -> RegionKind {
+        match typ {
+            1 => RegionKind::Usable,
+            3 => RegionKind::AcpiReclaimable,
+            4 => RegionKind::AcpiNvs,
+            5 => RegionKind::BadMemory,
+            _ => RegionKind::Reserved,
+        }
+    }
+
+    /// Tests if the kind belongs to frames the allocators manage.
+    pub fn is_ram(&self) -> bool {
+        *self == RegionKind::Usable || *self == RegionKind::InUse
+    }
+}
+
+/// The `fmt::Display` implementation for `RegionKind`.
+impl fmt::Display for RegionKind {
+    /// Formats the region kind.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            RegionKind::Usable => "Usable",
+            RegionKind::InUse => "In use",
+            RegionKind::AcpiReclaimable => "ACPI reclaimable",
+            RegionKind::AcpiNvs => "ACPI NVS",
+            RegionKind::Reserved => "Reserved",
+            RegionKind::BadMemory => "Bad memory",
+        })
+    }
+}
+
+/// The `MemoryRegion` type.
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryRegion {
+    /// The start address.
+    pub start: PhysicalAddress,
+
+    /// The end address, exclusive.
+    pub end: PhysicalAddress,
+
+    /// The kind.
+    pub kind: RegionKind,
+}
+
+/// The `MemoryRegion` implementation.
+impl MemoryRegion {
+    /// Gets the index of the first frame entirely inside the region.
+    pub fn first_frame(&self) -> usize {
+        self.start.div_ceil(PAGE_SIZE)
+    }
+
+    /// Gets the index of the frame after the last
+    /// frame entirely inside the region.
+    pub fn end_frame(&self) -> usize {
+        self.end / PAGE_SIZE
+    }
+
+    /// Gets the number of frames entirely inside the region.
+    pub fn frame_count(&self) -> usize {
+        self.end_frame().saturating_sub(self.first_frame())
+    }
+}
+
+/// The `MemoryMap` type.
+///
+/// Holds the firmware memory map as sorted, non-overlapping
+/// regions, with the reserved regions split out of usable memory.
+#[derive(Copy)]
+pub struct MemoryMap {
+    /// The regions reported by the firmware.
+    firmware: [MemoryRegion; MAX_MEMORY_REGIONS],
+
+    /// The number of firmware regions.
+    firmware_count: usize,
+
+    /// The normalised regions, sorted by address.
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+
+    /// The number of normalised regions.
+    count: usize,
+
+    /// The regions in use since boot.
+    reserved: ReservedRegions,
+}
+
+/// The `Clone` implementation for `MemoryMap`.
+impl Clone for MemoryMap {
+    /// Clones the memory map.
+    fn clone(&self) -> MemoryMap {
+        *self
+    }
+}
+
+/// The `MemoryMap` implementation.
+impl MemoryMap {
+    /// Constructs a new empty `MemoryMap`.
+    pub const fn empty() -> MemoryMap {
+        MemoryMap {
+            firmware: [EMPTY_REGION; MAX_MEMORY_REGIONS],
+            firmware_count: 0,
+            regions: [EMPTY_REGION; MAX_MEMORY_REGIONS],
+            count: 0,
+            reserved: ReservedRegions::new(),
+        }
+    }
+
+    /// Loads the multiboot2 memory map tag and normalises
+    /// it around the reserved regions.
+    ///
+    /// Fills the map in place, as it is too large for the boot stack.
+    pub fn load(&mut self, multiboot2_addr: usize) {
+        // Find the memory map tag
+        let mut tag = multiboot2_addr + 8;
+        loop {
+            let (typ, size) = unsafe { (*(tag as *const u32), *((tag + 4) as *const u32) as usize) };
+            match typ {
+                TAG_END => panic!("Memory map tag required"),
+                TAG_MEMORY_MAP => {
+                    self.read_firmware_regions(tag, size);
+                    break;
+                }
+                _ => tag += (size + 7) & !7,
+            }
+        }
+
+        self.normalise();
+    }
+
+    /// Reads the entries of the memory map tag.
+    fn read_firmware_regions(&mut self, tag: usize, size: usize) {
+        let entry_size = unsafe { *((tag + 8) as *const u32) } as usize;
+        let mut entry = tag + 16;
+        while entry + entry_size <= tag + size {
+            let (base, length, typ) = unsafe {
+                (*(entry as *const u64) as usize,
+                 *((entry + 8) as *const u64) as usize,
+                 *((entry + 16) as *const u32))
+            };
+            entry += entry_size;
+            if length == 0 {
+                continue;
+            }
+            assert!(self.firmware_count < MAX_MEMORY_REGIONS,
+                    "Too many memory regions");
+            self.firmware[self.firmware_count] = MemoryRegion {
+                start: base,
+                end: base + length,
+                kind: RegionKind::from_area_type(typ),
+            };
+            self.firmware_count += 1;
+        }
+    }
+
+    /// Rebuilds the normalised regions from the firmware
+    /// regions and the reserved regions.
+    ///
+    /// Overlaps are resolved in favour of the greater kind,
+    /// and adjacent regions of the same kind are merged.
+    fn normalise(&mut self) {
+        // Classify the range between every pair of adjacent boundaries
+        self.count = 0;
+        let mut next = self.boundaries().min();
+        while let Some(start) = next {
+            next = self.boundaries().filter(|&addr| addr > start).min();
+            let end = match next {
+                Some(end) => end,
+                None => break,
+            };
+            let kind = self.firmware[..self.firmware_count]
+                .iter()
+                .filter(|region| region.start <= start && end <= region.end)
+                .map(|region| region.kind)
+                .max();
+            let kind = match kind {
+                Some(RegionKind::Usable) if self.reserved.overlapping(start, end).is_some() => {
+                    RegionKind::InUse
+                }
+                Some(kind) => kind,
+                None => continue,
+            };
+
+            // Merge the range with the previous region if possible
+            if self.count > 0 {
+                let last = &mut self.regions[self.count - 1];
+                if last.end == start && last.kind == kind {
+                    last.end = end;
+                    continue;
+                }
+            }
+            assert!(self.count < MAX_MEMORY_REGIONS, "Too many memory regions");
+            self.regions[self.count] = MemoryRegion {
+                start,
+                end,
+                kind,
+            };
+            self.count += 1;
+        }
+    }
+
+    /// Gets an iterator over the boundaries of the firmware
+    /// regions and the reserved regions, in no particular order.
+    ///
+    /// Searched instead of sorted, to keep them off the stack.
+    fn boundaries<'a>(&'a self) -> impl Iterator<Item = PhysicalAddress> + 'a {
+        let firmware = self.firmware[..self.firmware_count]
+            .iter()
+            .flat_map(|region| once(region.start).chain(once(region.end)));
+        let reserved = self.reserved
+            .iter()
+            .flat_map(|region| once(region.start).chain(once(region.end)));
+        firmware.chain(reserved)
+    }
+
+    /// Reserves a region and splits it out of usable memory.
+    pub fn reserve(&mut self,
+                   start: PhysicalAddress,
+                   end: PhysicalAddress,
+                   name: &'static str)
+                   -> Result<(), ReservedError> {
+        self.reserved.add(start, end, name)?;
+        self.normalise();
+        Ok(())
+    }
+
+    /// Gets the reserved regions as mutable.
+    ///
+    /// Only meant to fill them before `load`.
+    pub fn reserved_mut(&mut self) -> &mut ReservedRegions {
+        &mut self.reserved
+    }
+
+    /// Gets an iterator over the regions.
+    pub fn iter(&self) -> slice::Iter<'_, MemoryRegion> {
+        self.regions[..self.count].iter()
+    }
+
+    /// Gets an iterator over the usable regions.
+    pub fn usable(&self) -> Filter<slice::Iter<'_, MemoryRegion>, fn(&&MemoryRegion) -> bool> {
+        fn is_usable(region: &&MemoryRegion) -> bool {
+            region.kind == RegionKind::Usable
+        }
+        self.iter().filter(is_usable as fn(&&MemoryRegion) -> bool)
+    }
+
+    /// Gets the end of the highest region of RAM.
+    pub fn ram_end(&self) -> PhysicalAddress {
+        self.iter()
+            .filter(|region| region.kind.is_ram())
+            .map(|region| region.end)
+            .max()
+            .expect("No usable memory regions")
+    }
+
+    /// Finds a page-aligned, usable region of the specified size
+    /// that lies within the physical memory mapped at boot.
+    pub fn find_gap(&self, size: usize) -> Option<PhysicalAddress> {
+        self.usable()
+            .map(|region| ((region.start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), region.end))
+            .find(|&(start, end)| start + size <= end && start + size <= PHYS_MAP_END)
+            .map(|(start, _)| start)
+    }
+}
+
+/// The `fmt::Display` implementation for `MemoryMap`.
+impl fmt::Display for MemoryMap {
+    /// Formats the memory map, one region per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in self.iter() {
+            write!(f,
+                   "\t0x{:012x} - 0x{:012x} {:<16}",
+                   region.start,
+                   region.end,
+                   region.kind)?;
+            for reserved in self.reserved.iter() {
+                if region.kind == RegionKind::InUse && reserved.start < region.end &&
+                   region.start < reserved.end {
+                    write!(f, " {}", reserved.name)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a normalised map from firmware regions.
+    fn map(firmware: &[(PhysicalAddress, PhysicalAddress, RegionKind)]) -> MemoryMap {
+        let mut map = MemoryMap::empty();
+        for &(start, end, kind) in firmware {
+            map.firmware[map.firmware_count] = MemoryRegion {
+                start,
+                end,
+                kind,
+            };
+            map.firmware_count += 1;
+        }
+        map.normalise();
+        map
+    }
+
+    /// Gets the normalised regions of a map.
+    fn regions(map: &MemoryMap) -> Vec<(PhysicalAddress, PhysicalAddress, RegionKind)> {
+        map.iter().map(|region| (region.start, region.end, region.kind)).collect()
+    }
+
+    #[test]
+    fn normalise_sorts_regions_and_skips_holes() {
+        let map = map(&[(0x10_0000, 0x20_0000, RegionKind::Usable),
+                        (0x0, 0x9_f000, RegionKind::Usable)]);
+        assert_eq!(regions(&map),
+                   [(0x0, 0x9_f000, RegionKind::Usable),
+                    (0x10_0000, 0x20_0000, RegionKind::Usable)]);
+    }
+
+    #[test]
+    fn normalise_merges_adjacent_regions_of_the_same_kind() {
+        let map = map(&[(0x0, 0x1000, RegionKind::Usable),
+                        (0x1000, 0x3000, RegionKind::Usable),
+                        (0x3000, 0x4000, RegionKind::Reserved)]);
+        assert_eq!(regions(&map),
+                   [(0x0, 0x3000, RegionKind::Usable),
+                    (0x3000, 0x4000, RegionKind::Reserved)]);
+    }
+
+    #[test]
+    fn normalise_resolves_overlaps_in_favour_of_the_greater_kind() {
+        let map = map(&[(0x0, 0x8000, RegionKind::Usable),
+                        (0x2000, 0x4000, RegionKind::AcpiReclaimable),
+                        (0x3000, 0x5000, RegionKind::Reserved),
+                        (0x7000, 0x9000, RegionKind::BadMemory)]);
+        assert_eq!(regions(&map),
+                   [(0x0, 0x2000, RegionKind::Usable),
+                    (0x2000, 0x3000, RegionKind::AcpiReclaimable),
+                    (0x3000, 0x5000, RegionKind::Reserved),
+                    (0x5000, 0x7000, RegionKind::Usable),
+                    (0x7000, 0x9000, RegionKind::BadMemory)]);
+    }
+
+    #[test]
+    fn reserve_splits_regions_out_of_usable_memory() {
+        let mut map = map(&[(0x0, 0x8000, RegionKind::Usable),
+                            (0x8000, 0x9000, RegionKind::AcpiNvs)]);
+        map.reserve(0x2000, 0x3000, "kernel").unwrap();
+        map.reserve(0x7000, 0x9000, "multiboot").unwrap();
+        assert_eq!(regions(&map),
+                   [(0x0, 0x2000, RegionKind::Usable),
+                    (0x2000, 0x3000, RegionKind::InUse),
+                    (0x3000, 0x7000, RegionKind::Usable),
+                    (0x7000, 0x8000, RegionKind::InUse),
+                    (0x8000, 0x9000, RegionKind::AcpiNvs)]);
+        assert_eq!(map.usable().count(), 2);
+        assert_eq!(map.ram_end(), 0x8000);
+    }
+}
@@ -0,0 +1,180 @@
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpu;
+use interrupts;
+use memory::{self, PAGE_SIZE, Frame, VirtualRegion, Backing, Placement, VmaError};
+use memory::paging::{PhysicalAddress, VirtualAddress, Page, EntryFlags};
+
+/// The start address of the I/O remapping window.
+///
+/// Located between the kernel stacks and the placement
+/// window of the kernel address space.
+pub const IOREMAP_START: VirtualAddress = memory::KERNEL_WINDOWS + 0x1_8000_0000;
+
+/// The size of the I/O remapping window.
+pub const IOREMAP_SIZE: usize = 1024 * 1024 * 1024;
+
+/// The page attribute table programmed at boot.
+///
+/// The first four entries keep the power-on defaults (write-back,
+/// write-through, UC-, uncacheable). The upper four repeat them,
+/// except that entry 5 selects write-combining.
+const PAT_VALUE: u64 = 0x0007_0106_0007_0406;
+
+/// Whether the page attribute table has been programmed.
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Programs the page attribute table if the CPU supports it.
+pub fn init() {
+    if cpu::has_pat() {
+        unsafe { cpu::set_pat(PAT_VALUE) };
+        PAT_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The `CacheMode` enum.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum CacheMode {
+    /// Reads and writes are cached.
+    WriteBack,
+
+    /// Reads are cached, writes go straight to memory.
+    WriteThrough,
+
+    /// Writes are buffered and combined, reads are not cached.
+    ///
+    /// Falls back to `Uncached` without a page attribute table.
+    WriteCombining,
+
+    /// Nothing is cached.
+    Uncached,
+}
+
+/// The `CacheMode` implementation.
+impl CacheMode {
+    /// Gets the entry flags selecting the memory type.
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheMode::WriteBack => EntryFlags::empty(),
+            CacheMode::WriteThrough => EntryFlags::WRITE_THROUGH,
+            // The PAT bit of 4 KiB pages shares its position with `HUGE_PAGE`
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::SeqCst) => {
+                EntryFlags::HUGE_PAGE | EntryFlags::WRITE_THROUGH
+            }
+            CacheMode::WriteCombining | CacheMode::Uncached => {
+                EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Maps a physical memory-mapped I/O range into the I/O remapping window.
+///
+/// The mapping is writable, non-executable and uses the specified
+/// cache mode. It goes away when the returned handle is dropped.
+pub fn ioremap(phys: PhysicalAddress, len: usize, cache_mode: CacheMode) -> Result<Mmio, VmaError> {
+    assert!(len > 0);
+    let offset = phys % PAGE_SIZE;
+    let size = memory::page_align(offset + len);
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | cache_mode.flags();
+
+    interrupts::without_interrupts(|| {
+        memory::with_controller(|mc| {
+            let start = mc.address_space
+                .find_free_in(IOREMAP_START,
+                              IOREMAP_START + IOREMAP_SIZE,
+                              size,
+                              Placement::FirstFit)?;
+            mc.address_space.add(VirtualRegion::new(start, size, flags, Backing::Fixed))?;
+            for (index, page) in Page::range_containing(start, start + size).enumerate() {
+                let frame = Frame::get_frame_for_address(phys - offset + index * PAGE_SIZE);
+                if let Err(err) = mc.active_table
+                    .map_to(page, frame, flags, &mut mc.frame_allocator) {
+                    mc.unmap_fixed(start, size).unwrap();
+                    return Err(VmaError::Map(err));
+                }
+            }
+            Ok(Mmio {
+                addr: start + offset,
+                phys,
+                len,
+            })
+        })
+    })
+}
+
+/// The `Mmio` type.
+///
+/// Represents a memory-mapped I/O range mapped by `ioremap`.
+/// The mapping must not be dropped while the memory
+/// controller is locked.
+#[derive(Debug)]
+pub struct Mmio {
+    /// The virtual address of the range.
+    addr: VirtualAddress,
+
+    /// The physical address of the range.
+    phys: PhysicalAddress,
+
+    /// The length in bytes.
+    len: usize,
+}
+
+/// The `Mmio` implementation.
+impl Mmio {
+    /// Gets the virtual address.
+    pub fn addr(&self) -> VirtualAddress {
+        self.addr
+    }
+
+    /// Gets the physical address.
+    #[allow(dead_code)]
+    pub fn phys(&self) -> PhysicalAddress {
+        self.phys
+    }
+
+    /// Gets the length in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tests if the mapping is empty.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads a value at the specified offset.
+    #[allow(dead_code)]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= self.len, "MMIO read out of bounds");
+        unsafe { ptr::read_volatile((self.addr + offset) as *const T) }
+    }
+
+    /// Writes a value at the specified offset.
+    #[allow(dead_code)]
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(offset + mem::size_of::<T>() <= self.len, "MMIO write out of bounds");
+        unsafe { ptr::write_volatile((self.addr + offset) as *mut T, value) }
+    }
+
+    /// Keeps the range mapped for good and returns its virtual address.
+    pub fn leak(self) -> VirtualAddress {
+        let addr = self.addr;
+        mem::forget(self);
+        addr
+    }
+}
+
+/// The `Drop` implementation for `Mmio`.
+impl Drop for Mmio {
+    /// Unmaps the range.
+    fn drop(&mut self) {
+        let start = Page::get_page_at_address(self.addr).address();
+        let size = memory::page_align(self.addr + self.len) - start;
+        interrupts::without_interrupts(|| {
+            memory::with_controller(|mc| mc.unmap_fixed(start, size).expect("MMIO range not mapped"))
+        });
+    }
+}
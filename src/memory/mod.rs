@@ -1,10 +1,387 @@
+use spin::Mutex;
+use multiboot2;
+
 /// The size of a page.
 pub const PAGE_SIZE: usize = 4096;
 
-mod area_alloc;
-pub use self::area_alloc::AreaFrameAllocator;
-mod paging;
-use self::paging::PhysicalAddress;
+mod buddy_alloc;
+mod reserved;
+mod map;
+mod address_space;
+mod refcount;
+pub use self::buddy_alloc::BuddyFrameAllocator;
+pub use self::reserved::{ReservedRegions, ReservedError};
+pub use self::map::{MemoryMap, RegionKind};
+pub use self::address_space::{AddressSpace, VirtualRegion, Backing, Placement, VmaError};
+pub use self::refcount::FrameRefCounts;
+pub mod paging;
+pub mod heap;
+pub mod slab;
+pub mod stack;
+pub mod fault;
+pub mod mmio;
+use core::ptr;
+use self::paging::{PhysicalAddress, VirtualAddress, ActivePageTable, Page, MapError};
+use self::paging::EntryFlags;
+
+/// The virtual address the kernel is linked at.
+///
+/// The first GiB of physical memory is mapped here.
+pub const KERNEL_OFFSET: VirtualAddress = 0xffff_ffff_8000_0000;
+
+/// The end of the physical memory mapped at `KERNEL_OFFSET`.
+///
+/// Allocator metadata has to be reachable through it.
+const PHYS_MAP_END: PhysicalAddress = 1024 * 1024 * 1024;
+
+/// Gets the virtual address of the specified physical address
+/// inside the physical memory mapped at `KERNEL_OFFSET`.
+pub fn phys_to_virt(addr: PhysicalAddress) -> VirtualAddress {
+    assert!(addr < PHYS_MAP_END, "Physical address not mapped");
+    addr + KERNEL_OFFSET
+}
+
+/// Gets the physical address of the specified virtual address
+/// inside the physical memory mapped at `KERNEL_OFFSET`.
+pub fn virt_to_phys(addr: VirtualAddress) -> PhysicalAddress {
+    assert!(addr >= KERNEL_OFFSET, "Virtual address not in the higher half");
+    addr - KERNEL_OFFSET
+}
+
+/// The start of the window user regions are placed in.
+pub const USER_START: VirtualAddress = 0x40_0000;
+
+/// The end of the lower half, which holds the user regions.
+pub const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+/// The start of the higher half P4 entry holding the kernel windows.
+///
+/// Holds the temporary pages, the heap, the slabs, the kernel stacks,
+/// the I/O remapping window and the placement window. Its P3 table
+/// is allocated by `paging::remap_kernel`, so every page table
+/// created afterwards shares the windows.
+pub const KERNEL_WINDOWS: VirtualAddress = 0xffff_fe80_0000_0000;
+
+/// The start of the window new kernel regions are placed in.
+pub const MMAP_START: VirtualAddress = KERNEL_WINDOWS + 0x2_0000_0000;
+
+/// The end of the window new kernel regions are placed in.
+pub const MMAP_END: VirtualAddress = KERNEL_WINDOWS + 0x80_0000_0000;
+
+/// The memory controller.
+///
+/// Built in place by `init`, as it is too large for the boot stack.
+static MEMORY_CONTROLLER: Mutex<MemoryController> = Mutex::new(MemoryController::empty());
+
+/// Initializes the memory controller.
+pub fn init(multiboot2_addr: usize) {
+    // Get the multiboot2 data
+    let mb2_info = unsafe { multiboot2::load(multiboot2_addr) };
+    let elf_sections = mb2_info.elf_sections_tag().expect("ELF sections tag required");
+
+    // Get the physical kernel memory bounds and every other region in use
+    let kernel_start = elf_sections.sections()
+        .map(|s| section_phys_addr(s.addr as usize))
+        .min()
+        .unwrap();
+    let kernel_end = elf_sections.sections()
+        .map(|s| section_phys_addr((s.addr + s.size) as usize))
+        .max()
+        .unwrap();
+    let mut controller = MEMORY_CONTROLLER.lock();
+    let mc = &mut *controller;
+    reserved::collect(mc.memory_map.reserved_mut(),
+                      multiboot2_addr,
+                      kernel_start,
+                      kernel_end)
+        .expect("Too many reserved regions");
+
+    // Normalise the firmware memory map around the reserved regions
+    mc.memory_map.load(multiboot2_addr);
+
+    // Create the frame allocator
+    mc.frame_refs = FrameRefCounts::new(&mut mc.memory_map);
+    mc.frame_allocator = BuddyFrameAllocator::new(&mut mc.memory_map);
+    paging::init();
+
+    // Remap the kernel, keeping the allocator metadata reachable
+    let metadata = mc.frame_allocator.metadata_region();
+    paging::remap_kernel(&mut mc.active_table,
+                         &mut mc.frame_allocator,
+                         &mb2_info,
+                         multiboot2_addr,
+                         &[metadata, mc.frame_refs.region()]);
+
+    // Keep new regions clear of the windows of the kernel allocators
+    let windows = [(heap::HEAP_START, heap::HEAP_MAX_SIZE),
+                   (slab::SLAB_START, slab::SLAB_MAX_SIZE),
+                   (stack::STACK_AREA_START, stack::STACK_AREA_SIZE)];
+    for &(start, size) in windows.iter() {
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let region = VirtualRegion::new(start, size, flags, Backing::Fixed);
+        mc.address_space.add(region).expect("Kernel windows overlap");
+    }
+    mc.initialized = true;
+    drop(controller);
+
+    // Set the kernel heap up
+    heap::init();
+
+    // Make write-combining available to drivers
+    mmio::init();
+}
+
+/// Runs the closure with the memory controller.
+///
+/// The closure may not allocate from or free to the kernel heap or
+/// the slabs, as they map and unmap their pages through the memory
+/// controller. Doing so panics instead of deadlocking.
+pub fn with_controller<F, R>(f: F) -> R
+    where F: FnOnce(&mut MemoryController) -> R
+{
+    let mut controller = MEMORY_CONTROLLER.lock();
+    assert!(controller.initialized, "Memory controller not initialized");
+    f(&mut controller)
+}
+
+/// Runs the closure with the memory controller
+/// on behalf of the kernel heap or the slabs.
+///
+/// Panics if the memory controller is held, which means something
+/// allocated or freed while holding it. Waiting would never return.
+fn with_controller_for_alloc<F, R>(f: F) -> R
+    where F: FnOnce(&mut MemoryController) -> R
+{
+    let mut controller = MEMORY_CONTROLLER.try_lock()
+        .expect("Heap used while holding the memory controller");
+    assert!(controller.initialized, "Memory controller not initialized");
+    f(&mut controller)
+}
+
+/// The `MemoryController` type.
+pub struct MemoryController {
+    /// The active page table.
+    pub active_table: ActivePageTable,
+
+    /// The frame allocator.
+    pub frame_allocator: BuddyFrameAllocator,
+
+    /// The physical memory map.
+    pub memory_map: MemoryMap,
+
+    /// The virtual regions of the higher half,
+    /// shared by every page table.
+    pub address_space: AddressSpace,
+
+    /// The reference counts of shared frames.
+    pub frame_refs: FrameRefCounts,
+
+    /// Whether `init` has set the controller up.
+    initialized: bool,
+}
+
+/// The `MemoryController` implementation.
+impl MemoryController {
+    /// Constructs a new `MemoryController` managing no memory.
+    const fn empty() -> MemoryController {
+        MemoryController {
+            active_table: unsafe { ActivePageTable::new() },
+            frame_allocator: BuddyFrameAllocator::empty(),
+            memory_map: MemoryMap::empty(),
+            address_space: AddressSpace::new(MMAP_START, MMAP_END),
+            frame_refs: FrameRefCounts::empty(),
+            initialized: false,
+        }
+    }
+
+    /// Reserves an anonymous region of at least the specified size.
+    ///
+    /// Fixed placements go to the address space owning the address.
+    /// Otherwise, regions with `USER_ACCESSIBLE` flags are placed in
+    /// the lower half of the active page table, and all others in the
+    /// shared higher half. The pages are mapped on the first access
+    /// or by `commit`.
+    #[allow(dead_code)]
+    pub fn reserve(&mut self,
+                   size: usize,
+                   flags: EntryFlags,
+                   placement: Placement)
+                   -> Result<VirtualAddress, VmaError> {
+        if size == 0 {
+            return Err(VmaError::EmptyRange);
+        }
+        let size = page_align(size);
+        let space = match placement {
+            Placement::Fixed(start) => self.address_space_of_mut(start),
+            _ if flags.contains(EntryFlags::USER_ACCESSIBLE) => {
+                self.active_table.address_space_mut()
+            }
+            _ => &mut self.address_space,
+        };
+        let start = space.find_free(size, placement)?;
+        space.add(VirtualRegion::new(start, size, flags, Backing::Anonymous))?;
+        Ok(start)
+    }
+
+    /// Finds the region containing the specified address.
+    pub fn find_region(&self, addr: VirtualAddress) -> Option<&VirtualRegion> {
+        self.address_space_of(addr).find(addr)
+    }
+
+    /// Gets the address space owning the specified address.
+    ///
+    /// Lower half regions belong to the active page table,
+    /// higher half ones are shared by every page table.
+    fn address_space_of(&self, addr: VirtualAddress) -> &AddressSpace {
+        if addr < USER_END {
+            self.active_table.address_space()
+        } else {
+            &self.address_space
+        }
+    }
+
+    /// Gets the address space owning the specified address as mutable.
+    fn address_space_of_mut(&mut self, addr: VirtualAddress) -> &mut AddressSpace {
+        if addr < USER_END {
+            self.active_table.address_space_mut()
+        } else {
+            &mut self.address_space
+        }
+    }
+
+    /// Maps every page of the range that is not mapped yet.
+    ///
+    /// The range must lie inside anonymous regions.
+    #[allow(dead_code)]
+    pub fn commit(&mut self, start: VirtualAddress, size: usize) -> Result<(), VmaError> {
+        for page in Page::range_containing(start, start + size) {
+            let flags = match self.find_region(page.address()) {
+                Some(region) if region.backing == Backing::Anonymous => region.flags,
+                _ => return Err(VmaError::NotReserved),
+            };
+            if self.active_table.translate_page(page).is_none() {
+                self.map_zeroed(page, flags).map_err(VmaError::Map)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the range and removes it from the regions
+    /// of the address space owning it.
+    ///
+    /// Frames go back to the frame allocator unless another address
+    /// space still shares them. Fixed regions are left to their
+    /// owners, which remove them with `unmap_fixed`.
+    #[allow(dead_code)]
+    pub fn munmap(&mut self, start: VirtualAddress, size: usize) -> Result<(), VmaError> {
+        let end = start + page_align(size);
+        if self.address_space_of(start).overlaps_fixed(start, end) {
+            return Err(VmaError::FixedRegion);
+        }
+        self.unmap_regions(start, end)
+    }
+
+    /// Unmaps the range of a fixed region on behalf of its owner
+    /// and removes it from the regions.
+    ///
+    /// The frames stay with the owner.
+    pub fn unmap_fixed(&mut self, start: VirtualAddress, size: usize) -> Result<(), VmaError> {
+        let end = start + page_align(size);
+        self.unmap_regions(start, end)
+    }
+
+    /// Unmaps the range and removes it from the regions.
+    fn unmap_regions(&mut self, start: VirtualAddress, end: VirtualAddress) -> Result<(), VmaError> {
+        self.address_space_of_mut(start).split_range(start, end)?;
+        while let Some(&region) = self.address_space_of(start).overlapping(start, end) {
+            self.address_space_of_mut(start).remove(region.start);
+            for page in Page::range_containing(region.start, region.end()) {
+                if self.active_table.translate_page(page).is_none() {
+                    continue;
+                }
+                let frame = self.active_table.unmap_keep_frame(page, &mut self.frame_allocator);
+                if region.backing == Backing::Anonymous && self.frame_refs.release(&frame) {
+                    self.frame_allocator.dealloc_frame(frame);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the flags of the range in the
+    /// address space owning it.
+    ///
+    /// Mapped pages get the new flags right away, except that
+    /// copy-on-write pages stay read-only until written to. The
+    /// regions only take the flags once every page did, and the
+    /// pages get their old flags back if one of them fails.
+    /// Fixed regions keep the flags their owner chose.
+    #[allow(dead_code)]
+    pub fn mprotect(&mut self,
+                    start: VirtualAddress,
+                    size: usize,
+                    flags: EntryFlags)
+                    -> Result<(), VmaError> {
+        let end = start + page_align(size);
+        if self.address_space_of(start).overlaps_fixed(start, end) {
+            return Err(VmaError::FixedRegion);
+        }
+        self.address_space_of_mut(start).split_range(start, end)?;
+        for page in Page::range_containing(start, end) {
+            if let Err(err) = self.protect_page(page, flags) {
+                for done in Page::range(Page::get_page_at_address(start), page) {
+                    let old_flags = self.find_region(done.address()).unwrap().flags;
+                    let _ = self.protect_page(done, old_flags);
+                }
+                return Err(VmaError::Map(err));
+            }
+        }
+        self.address_space_of_mut(start).set_flags(start, end, flags);
+        Ok(())
+    }
+
+    /// Gives the page the flags if it is mapped,
+    /// keeping copy-on-write pages read-only.
+    fn protect_page(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        let mut page_flags = flags;
+        match self.active_table.page_flags(page) {
+            Some(old_flags) if old_flags.contains(EntryFlags::COPY_ON_WRITE) => {
+                page_flags.remove(EntryFlags::WRITABLE);
+                page_flags.insert(EntryFlags::COPY_ON_WRITE);
+            }
+            Some(_) => (),
+            None => return Ok(()),
+        }
+        self.active_table.update_flags(Page::range(page, page.next()), page_flags)
+    }
+
+    /// Maps a zeroed frame to the page and applies the flags.
+    fn map_zeroed(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        self.active_table
+            .map(page, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, &mut self.frame_allocator)?;
+        unsafe {
+            ptr::write_bytes(page.address() as *mut u8, 0, PAGE_SIZE);
+        }
+        self.active_table.update_flags(Page::range(page, page.next()), flags)
+    }
+}
+
+/// Gets the physical address of an address inside a kernel section.
+///
+/// The boot sections are linked at their physical addresses,
+/// every other section at `KERNEL_OFFSET` above it.
+pub fn section_phys_addr(addr: usize) -> PhysicalAddress {
+    if addr >= KERNEL_OFFSET {
+        virt_to_phys(addr)
+    } else {
+        addr
+    }
+}
+
+/// Rounds the size up to whole pages.
+fn page_align(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
 
 /// The `Frame` type.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -42,6 +419,19 @@ impl Frame {
     }
 }
 
+/// The `FrameStats` type.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameStats {
+    /// The number of usable frames.
+    pub total: usize,
+
+    /// The number of free frames.
+    pub free: usize,
+
+    /// The number of usable frames reserved at boot.
+    pub reserved: usize,
+}
+
 /// The `FrameAllocator` trait.
 pub trait FrameAllocator {
     /// Allocates a frame.
@@ -49,4 +439,27 @@ pub trait FrameAllocator {
 
     /// Deallocates a frame.
     fn dealloc_frame(&mut self, frame: Frame);
+
+    /// Allocates `2^order` contiguous frames,
+    /// aligned to their combined size.
+    ///
+    /// Allocators that only hand out single frames
+    /// can't satisfy orders above zero.
+    fn alloc_frames(&mut self, order: usize) -> Option<Frame> {
+        if order == 0 {
+            self.alloc_frame()
+        } else {
+            None
+        }
+    }
+
+    /// Deallocates `2^order` contiguous frames
+    /// allocated with `alloc_frames`.
+    ///
+    /// Hands the frames back one at a time by default.
+    fn dealloc_frames(&mut self, frame: Frame, order: usize) {
+        for index in frame.index..frame.index + (1 << order) {
+            self.dealloc_frame(Frame { index });
+        }
+    }
 }
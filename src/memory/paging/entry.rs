@@ -1,21 +1,28 @@
 use memory::Frame;
+use memory::paging::MapError;
 
 // The page table entry bit flags
 bitflags! {
-    pub flags EntryFlags: u64 {
-        const PRESENT =         1 << 0,
-        const WRITABLE =        1 << 1,
-        const USER_ACCESSIBLE = 1 << 2,
-        const WRITE_THROUGH =   1 << 3,
-        const NO_CACHE =        1 << 4,
-        const ACCESSED =        1 << 5,
-        const DIRTY =           1 << 6,
-        const HUGE_PAGE =       1 << 7,
-        const GLOBAL =          1 << 8,
-        const NO_EXECUTE =      1 << 63,
+    pub struct EntryFlags: u64 {
+        const PRESENT =         1 << 0;
+        const WRITABLE =        1 << 1;
+        const USER_ACCESSIBLE = 1 << 2;
+        const WRITE_THROUGH =   1 << 3;
+        const NO_CACHE =        1 << 4;
+        const ACCESSED =        1 << 5;
+        const DIRTY =           1 << 6;
+        const HUGE_PAGE =       1 << 7;
+        const GLOBAL =          1 << 8;
+        const COPY_ON_WRITE =   1 << 9;
+        const NO_EXECUTE =      1 << 63;
     }
 }
 
+/// The PAT bit of a huge page entry.
+///
+/// Shares its position with the lowest address bit of other entries.
+const HUGE_PAT: u64 = 1 << 12;
+
 /// The `Entry` type.
 pub struct Entry(u64);
 
@@ -38,16 +45,37 @@ impl Entry {
 
     /// Gets the frame.
     pub fn frame(&self) -> Option<Frame> {
-        if self.flags().contains(PRESENT) {
+        if self.flags().contains(EntryFlags::PRESENT) {
             Some(Frame::get_frame_for_address(self.0 as usize & 0x000ffffffffff000))
         } else {
             None
         }
     }
 
+    /// Gets the first frame of a huge page entry,
+    /// without the PAT bit.
+    pub fn huge_frame(&self) -> Option<Frame> {
+        self.frame().map(|frame| {
+            Frame::get_frame_for_address(frame.get_start_address() & !(HUGE_PAT as usize))
+        })
+    }
+
+    /// Tests if the PAT bit of a huge page entry is set.
+    pub fn huge_pat(&self) -> bool {
+        self.0 & HUGE_PAT != 0
+    }
+
+    /// Sets the PAT bit of a huge page entry.
+    pub fn set_huge_pat(&mut self) {
+        self.0 |= HUGE_PAT;
+    }
+
     /// Sets entry flags for the specified frame.
-    pub fn set_flags(&mut self, frame: Frame, flags: EntryFlags) {
-        assert!(frame.get_start_address() & !0x000ffffffffff000 == 0);
+    pub fn set_flags(&mut self, frame: Frame, flags: EntryFlags) -> Result<(), MapError> {
+        if frame.get_start_address() & !0x000ffffffffff000 != 0 {
+            return Err(MapError::MisalignedFrame);
+        }
         self.0 = (frame.get_start_address() as u64) | flags.bits();
+        Ok(())
     }
 }
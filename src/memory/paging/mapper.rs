@@ -1,30 +1,69 @@
-use core::ptr::Unique;
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use core::arch::asm;
+use core::ptr::NonNull;
+use cpu;
+use super::{VirtualAddress, PhysicalAddress, Page, PageRange, ENTRY_COUNT};
+use super::{RECURSIVE_INDEX, KERNEL_P4_START, is_scratch_page, kernel_mapping_revoked};
 use super::entry::*;
-use super::table::{self, Table, Level4, Level1};
+use super::table::{self, Table, HierarchicalLevel, Level4};
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
 
+/// The number of 4 KiB pages in a 2 MiB page.
+pub const HUGE_2M_PAGES: usize = ENTRY_COUNT;
+
+/// The number of 4 KiB pages in a 1 GiB page.
+pub const HUGE_1G_PAGES: usize = ENTRY_COUNT * ENTRY_COUNT;
+
+/// The `MapError` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is already mapped.
+    AlreadyMapped,
+
+    /// The page is not mapped.
+    NotMapped,
+
+    /// The frame allocator ran out of frames.
+    OutOfFrames,
+
+    /// A huge page covers the page.
+    HugePageInTheWay,
+
+    /// The frame is not aligned to the page size.
+    MisalignedFrame,
+}
+
 /// The `Mapper` type.
 pub struct Mapper {
     /// The level4 page table.
-    p4: Unique<Table<Level4>>,
+    p4: NonNull<Table<Level4>>,
 }
 
+/// The `Send` implementation for `Mapper`.
+///
+/// The recursive mapping is only reached through the mapper
+/// owned by the active page table.
+unsafe impl Send for Mapper {}
+
 /// The `Mapper` implementation.
 impl Mapper {
     /// Constructs a new `Mapper`.
-    pub unsafe fn new() -> Mapper {
-        Mapper { p4: Unique::new(table::LEVEL4_TABLE) }
+    ///
+    /// # Safety
+    ///
+    /// Only one may exist at a time, as it
+    /// accesses the tables through the recursive mapping.
+    pub const unsafe fn new() -> Mapper {
+        Mapper { p4: NonNull::new_unchecked(table::LEVEL4_TABLE) }
     }
 
     /// Gets the level4 page table.
     pub fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.get() }
+        unsafe { self.p4.as_ref() }
     }
 
     /// Gets the level4 page table as mutable.
     pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.get_mut() }
+        unsafe { self.p4.as_mut() }
     }
 
     /// Translates a virtual address into a physical address.
@@ -35,58 +74,460 @@ impl Mapper {
     }
 
     /// Translates a page into a frame.
+    ///
+    /// Pages inside 2 MiB and 1 GiB pages translate
+    /// to the matching frame of the huge page.
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        use super::entry::HUGE_PAGE;
         let p3 = self.p4().next_table(page.p4_index());
-        let huge_page = || unimplemented!();
+        let huge_page = || {
+            p3.and_then(|p3| {
+                // Test if the page is inside a 1 GiB page
+                let p3_entry = &p3[page.p3_index()];
+                if let Some(start_frame) = p3_entry.huge_frame() {
+                    if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                        assert!(start_frame.index % HUGE_1G_PAGES == 0);
+                        return Some(Frame {
+                            index: start_frame.index + page.p2_index() * ENTRY_COUNT +
+                                   page.p1_index(),
+                        });
+                    }
+                }
+
+                // Test if the page is inside a 2 MiB page
+                if let Some(p2) = p3.next_table(page.p3_index()) {
+                    let p2_entry = &p2[page.p2_index()];
+                    if let Some(start_frame) = p2_entry.huge_frame() {
+                        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+                            assert!(start_frame.index % HUGE_2M_PAGES == 0);
+                            return Some(Frame { index: start_frame.index + page.p1_index() });
+                        }
+                    }
+                }
+                None
+            })
+        };
         p3.and_then(|p3| p3.next_table(page.p3_index()))
             .and_then(|p2| p2.next_table(page.p2_index()))
             .and_then(|p1| p1[page.p1_index()].frame())
+            .or_else(huge_page)
+    }
+
+    /// Gets the flags of a 4 KiB page.
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        self.p4()
+            .next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .and_then(|p2| p2.next_table(page.p2_index()))
+            .map(|p1| p1[page.p1_index()].flags())
+            .filter(|flags| flags.contains(EntryFlags::PRESENT))
     }
 
     /// Maps a page to a frame using the specified allocator.
-    pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    ///
+    /// The tables created on the way are freed if the mapping fails.
+    pub fn map_to<A>(&mut self,
+                     page: Page,
+                     frame: Frame,
+                     flags: EntryFlags,
+                     allocator: &mut A)
+                     -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let result = self.map_to_unchecked(page, frame, flags, allocator);
+        if result.is_err() {
+            self.free_empty_tables(page, allocator);
+        } else if is_scratch_page(page) {
+            invalidate(page.address());
+        }
+        result
+    }
+
+    /// Maps a page to a frame, creating the missing tables.
+    fn map_to_unchecked<A>(&mut self,
+                           page: Page,
+                           frame: Frame,
+                           flags: EntryFlags,
+                           allocator: &mut A)
+                           -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        let p3 = self.p4_mut().create_next_table(page.p4_index(), allocator)?;
+        let p2 = p3.create_next_table(page.p3_index(), allocator)?;
+        let p1 = p2.create_next_table(page.p2_index(), allocator)?;
+        if !p1[page.p1_index()].is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        p1[page.p1_index()].set_flags(frame, flags | EntryFlags::PRESENT)
+    }
+
+    /// Maps a 2 MiB page to 512 contiguous frames
+    /// using the specified allocator.
+    ///
+    /// The page and the frame must be 2 MiB aligned.
+    #[allow(dead_code)]
+    pub fn map_huge_2m<A>(&mut self,
+                          page: Page,
+                          frame: Frame,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+                          -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(page.index.is_multiple_of(HUGE_2M_PAGES), "Page not 2 MiB aligned");
+        if !frame.index.is_multiple_of(HUGE_2M_PAGES) {
+            return Err(MapError::MisalignedFrame);
+        }
+        let p3 = self.p4_mut().create_next_table(page.p4_index(), allocator)?;
+        let p2 = p3.create_next_table(page.p3_index(), allocator)?;
+        if !p2[page.p2_index()].is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        p2[page.p2_index()].set_flags(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+    }
+
+    /// Maps a 1 GiB page to 262144 contiguous frames
+    /// using the specified allocator.
+    ///
+    /// The page and the frame must be 1 GiB aligned,
+    /// and the CPU must support 1 GiB pages.
+    #[allow(dead_code)]
+    pub fn map_huge_1g<A>(&mut self,
+                          page: Page,
+                          frame: Frame,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+                          -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        assert!(cpu::has_1g_pages(), "1 GiB pages not supported");
+        assert!(page.index.is_multiple_of(HUGE_1G_PAGES), "Page not 1 GiB aligned");
+        if !frame.index.is_multiple_of(HUGE_1G_PAGES) {
+            return Err(MapError::MisalignedFrame);
+        }
+        let p3 = self.p4_mut().create_next_table(page.p4_index(), allocator)?;
+        if !p3[page.p3_index()].is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        p3[page.p3_index()].set_flags(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE)
+    }
+
+    /// Splits the huge page containing the specified page
+    /// into pages of the next smaller size with the same flags.
+    ///
+    /// A 1 GiB page becomes 2 MiB pages, a 2 MiB page becomes
+    /// 4 KiB pages, and the PAT bit moves to where the new entries
+    /// keep it. Returns `false` if the page is not inside a huge
+    /// page. The huge page must not hold the code or the stack
+    /// performing the split.
+    #[allow(dead_code)]
+    pub fn split_huge_page<A>(&mut self, page: Page, allocator: &mut A) -> Result<bool, MapError>
         where A: FrameAllocator
     {
-        let mut p3 = self.p4_mut().create_next_table(page.p4_index(), allocator);
-        let mut p2 = p3.create_next_table(page.p3_index(), allocator);
-        let mut p1 = p2.create_next_table(page.p2_index(), allocator);
-        assert!(p1[page.p1_index()].is_unused());
-        p1[page.p1_index()].set_flags(frame, flags | PRESENT);
+        let p3 = match self.p4_mut().next_table_mut(page.p4_index()) {
+            Some(p3) => p3,
+            None => return Ok(false),
+        };
+
+        // Split a 1 GiB page into 2 MiB pages
+        let p3_entry_flags = p3[page.p3_index()].flags();
+        if p3_entry_flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE) {
+            let start_frame = p3[page.p3_index()].huge_frame().unwrap();
+            let pat = p3[page.p3_index()].huge_pat();
+            replace_huge_page(p3, page.p3_index(), allocator, |entry, index| {
+                let frame = Frame { index: start_frame.index + index * HUGE_2M_PAGES };
+                entry.set_flags(frame, p3_entry_flags)?;
+                if pat {
+                    entry.set_huge_pat();
+                }
+                Ok(())
+            })?;
+            flush_all();
+            return Ok(true);
+        }
+
+        // Split a 2 MiB page into 4 KiB pages
+        let p2 = match p3.next_table_mut(page.p3_index()) {
+            Some(p2) => p2,
+            None => return Ok(false),
+        };
+        let p2_entry_flags = p2[page.p2_index()].flags();
+        if p2_entry_flags.contains(EntryFlags::PRESENT | EntryFlags::HUGE_PAGE) {
+            let start_frame = p2[page.p2_index()].huge_frame().unwrap();
+
+            // The PAT bit of 4 KiB pages shares its position with `HUGE_PAGE`
+            let mut flags = p2_entry_flags;
+            flags.remove(EntryFlags::HUGE_PAGE);
+            if p2[page.p2_index()].huge_pat() {
+                flags.insert(EntryFlags::HUGE_PAGE);
+            }
+            replace_huge_page(p2, page.p2_index(), allocator, |entry, index| {
+                entry.set_flags(Frame { index: start_frame.index + index }, flags)
+            })?;
+            flush_all();
+            return Ok(true);
+        }
+        Ok(false)
     }
 
     /// Maps the next free page using the specified allocator.
-    pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+    ///
+    /// The frame and the tables created on the way go back
+    /// to the allocator if the mapping fails.
+    pub fn map<A>(&mut self,
+                  page: Page,
+                  flags: EntryFlags,
+                  allocator: &mut A)
+                  -> Result<(), MapError>
         where A: FrameAllocator
     {
-        let frame = allocator.alloc_frame().unwrap();
-        self.map_to(page, frame, flags, allocator)
+        let frame = allocator.alloc_frame().ok_or(MapError::OutOfFrames)?;
+        let result = self.map_to(page, frame.clone(), flags, allocator);
+        if result.is_err() {
+            allocator.dealloc_frame(frame);
+        }
+        result
     }
 
     /// Identity maps a frame using the specified allocator.
-    pub fn identitiy_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A)
+    pub fn identitiy_map<A>(&mut self,
+                            frame: Frame,
+                            flags: EntryFlags,
+                            allocator: &mut A)
+                            -> Result<(), MapError>
         where A: FrameAllocator
     {
         let page = Page::get_page_at_address(frame.get_start_address());
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Maps every page of the range to a newly allocated frame.
+    ///
+    /// The pages mapped before a failure stay mapped.
+    #[allow(dead_code)]
+    pub fn map_range<A>(&mut self,
+                        pages: PageRange,
+                        flags: EntryFlags,
+                        allocator: &mut A)
+                        -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        for page in pages {
+            self.map(page, flags, allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Identity maps every page of the range.
+    ///
+    /// The pages mapped before a failure stay mapped.
+    #[allow(dead_code)]
+    pub fn identity_map_range<A>(&mut self,
+                                 pages: PageRange,
+                                 flags: EntryFlags,
+                                 allocator: &mut A)
+                                 -> Result<(), MapError>
+        where A: FrameAllocator
+    {
+        for page in pages {
+            self.identitiy_map(Frame { index: page.index }, flags, allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Unmaps every page of the range.
+    pub fn unmap_range<A>(&mut self, pages: PageRange, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for page in pages {
+            self.unmap(page, allocator);
+        }
+    }
+
+    /// Replaces the flags of every page of the range,
+    /// keeping the frames they are mapped to.
+    ///
+    /// Pages inside huge pages need to be split first.
+    pub fn update_flags(&mut self, pages: PageRange, flags: EntryFlags) -> Result<(), MapError> {
+        for page in pages {
+            let mapped = self.translate_page(page).is_some();
+            {
+                let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()));
+                let p1 = match p1 {
+                    Some(p1) => p1,
+                    None if mapped => return Err(MapError::HugePageInTheWay),
+                    None => return Err(MapError::NotMapped),
+                };
+                let frame = p1[page.p1_index()].frame().ok_or(MapError::NotMapped)?;
+                let old_flags = p1[page.p1_index()].flags();
+                p1[page.p1_index()].set_flags(frame, flags | EntryFlags::PRESENT)?;
+                if is_downgrade(old_flags, flags | EntryFlags::PRESENT) {
+                    kernel_mapping_revoked(page);
+                }
+            }
+            invalidate(page.address());
+        }
+        Ok(())
+    }
+
     /// Unmaps a page using the specified allocator.
+    ///
+    /// Frees the page tables that end up empty.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
         where A: FrameAllocator
+    {
+        let frame = self.unmap_keep_frame(page, allocator);
+        allocator.dealloc_frame(frame);
+    }
+
+    /// Unmaps a page and returns its frame instead of deallocating it.
+    ///
+    /// Frees the page tables that end up empty.
+    pub fn unmap_keep_frame<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator
     {
         assert!(self.translate(page.address()).is_some());
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .unwrap();
-        let frame = p1[page.p1_index()].frame().unwrap();
-        p1[page.p1_index()].mark_unused();
-        unsafe {
-            // Flush translation lookaside buffer
-            asm!("invlpg ($0)" :: "r" (page.address()) : "memory");
+        let frame = {
+            let p1 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                .expect("Page not mapped by a P1 table");
+            let frame = p1[page.p1_index()].frame().unwrap();
+            p1[page.p1_index()].mark_unused();
+            frame
+        };
+        invalidate(page.address());
+        kernel_mapping_revoked(page);
+        self.free_empty_tables(page, allocator);
+        frame
+    }
+
+    /// Frees the P1, P2 and P3 tables on the way
+    /// to the page as far as they exist and are empty.
+    ///
+    /// The P3 tables of the higher half stay, as
+    /// every page table shares them.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        // Never touch the tables of the recursive mapping
+        if page.p4_index() == RECURSIVE_INDEX {
+            return;
         }
-        allocator.dealloc_frame(frame);
+        let p4 = self.p4_mut();
+        {
+            let p3 = match p4.next_table_mut(page.p4_index()) {
+                Some(p3) => p3,
+                None => return,
+            };
+            if let Some(p2) = p3.next_table_mut(page.p3_index()) {
+                if let Some(p1) = p2.next_table(page.p2_index()) {
+                    if !p1.is_empty() {
+                        return;
+                    }
+                    free_table(p2, page.p2_index(), allocator);
+                }
+                if !p2.is_empty() {
+                    return;
+                }
+                free_table(p3, page.p3_index(), allocator);
+            }
+            if !p3.is_empty() || page.p4_index() >= KERNEL_P4_START {
+                return;
+            }
+        }
+        free_table(p4, page.p4_index(), allocator);
+    }
+}
+
+/// Replaces the huge page of the specified entry with a new
+/// table, whose entries the closure fills one by one.
+///
+/// The huge page is put back if filling the table fails.
+fn replace_huge_page<L, A, F>(table: &mut Table<L>,
+                              index: usize,
+                              allocator: &mut A,
+                              mut fill: F)
+                              -> Result<(), MapError>
+    where L: HierarchicalLevel,
+          A: FrameAllocator,
+          F: FnMut(&mut Entry, usize) -> Result<(), MapError>
+{
+    // Keep the raw frame, which holds the PAT bit
+    let huge_frame = table[index].frame().unwrap();
+    let huge_flags = table[index].flags();
+    let table_frame = allocator.alloc_frame().ok_or(MapError::OutOfFrames)?;
+    table[index].set_flags(table_frame, table_flags(huge_flags)).unwrap();
+    invalidate(table.next_table_addr(index).unwrap());
+    let result = {
+        let next_table = table.next_table_mut(index).unwrap();
+        (0..ENTRY_COUNT).try_for_each(|i| fill(&mut next_table[i], i))
+    };
+    if result.is_err() {
+        free_table(table, index, allocator);
+        table[index].set_flags(huge_frame, huge_flags).unwrap();
+    }
+    result
+}
+
+/// Frees the table the specified entry points to
+/// and marks the entry unused.
+fn free_table<L, A>(table: &mut Table<L>, index: usize, allocator: &mut A)
+    where L: HierarchicalLevel,
+          A: FrameAllocator
+{
+    let table_addr = table.next_table_addr(index).unwrap();
+    let frame = table[index].frame().unwrap();
+    table[index].mark_unused();
+    invalidate(table_addr);
+    allocator.dealloc_frame(frame);
+}
+
+/// Invalidates the TLB entry of the specified address
+/// for the active process-context identifier.
+fn invalidate(addr: VirtualAddress) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags));
+    }
+}
+
+/// Tests if TLB entries with the old flags permit
+/// accesses the new flags don't.
+///
+/// Granting write or user access and lifting the no-execute
+/// bit is fine, as stale entries only cause spurious page faults.
+fn is_downgrade(old_flags: EntryFlags, new_flags: EntryFlags) -> bool {
+    let ignored = EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::COPY_ON_WRITE;
+    let added = new_flags - old_flags - ignored;
+    let removed = old_flags - new_flags - ignored;
+    !(added - EntryFlags::WRITABLE - EntryFlags::USER_ACCESSIBLE).is_empty() ||
+    !(removed - EntryFlags::NO_EXECUTE).is_empty()
+}
+
+/// Gets the flags of an entry pointing to a table that
+/// replaces a huge page with the specified flags.
+///
+/// The new entries carry the actual permissions.
+fn table_flags(huge_flags: EntryFlags) -> EntryFlags {
+    let mut flags = EntryFlags::PRESENT | EntryFlags::WRITABLE;
+    if huge_flags.contains(EntryFlags::USER_ACCESSIBLE) {
+        flags.insert(EntryFlags::USER_ACCESSIBLE);
+    }
+    flags
+}
+
+/// Flushes the whole translation lookaside buffer
+/// of the active process-context identifier.
+///
+/// Only used after splitting huge pages, which keeps the
+/// translations, so the other identifiers may keep theirs.
+fn flush_all() {
+    unsafe {
+        let cr3: usize;
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
     }
 }
@@ -1,5 +1,11 @@
-use core::ptr::Unique;
+use core::arch::asm;
+use core::{mem, ptr};
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use multiboot2::BootInformation;
+use cpu;
+use interrupts;
 
 mod mapper;
 mod entry;
@@ -8,26 +14,365 @@ mod temp_page;
 
 pub use self::entry::*;
 pub use self::table::{Level1, Table};
+use self::table::Level2;
 
-use super::{Frame, PAGE_SIZE};
-use super::FrameAllocator;
-use self::table::{Level4, LEVEL4_TABLE};
+use super::{Frame, PAGE_SIZE, KERNEL_OFFSET, KERNEL_WINDOWS, phys_to_virt, virt_to_phys};
+use super::{FrameAllocator, FrameRefCounts, AddressSpace, Backing, USER_START, USER_END};
+use super::reserved::{VGA_BUFFER, VGA_BUFFER_SIZE};
+use self::table::LEVEL4_TABLE;
 use self::temp_page::TemporaryPage;
-use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapError};
+use self::mapper::HUGE_2M_PAGES;
 
 /// The number of entries.
 const ENTRY_COUNT: usize = 512;
 
+/// The P4 index of the recursive mapping.
+///
+/// The last entry holds the higher half kernel.
+const RECURSIVE_INDEX: usize = ENTRY_COUNT - 2;
+
+/// The first P4 index of the higher half.
+///
+/// The P3 tables of the higher half are shared by every page table.
+const KERNEL_P4_START: usize = ENTRY_COUNT / 2;
+
 /// The `PhysicalAddress` type.
 pub type PhysicalAddress = usize;
 
 /// The `VirtualAddress` type.
 pub type VirtualAddress = usize;
 
+/// The ELF section flag of writable sections.
+const ELF_SECTION_WRITABLE: u64 = 0x1;
+
+/// The ELF section flag of sections loaded into memory.
+const ELF_SECTION_ALLOCATED: u64 = 0x2;
+
+/// The ELF section flag of executable sections.
+const ELF_SECTION_EXECUTABLE: u64 = 0x4;
+
+/// The page used to edit inactive page tables.
+///
+/// Located in the first GiB of the kernel windows.
+const TEMPORARY_PAGE: VirtualAddress = KERNEL_WINDOWS;
+
+/// The number of process-context identifiers.
+const PCID_COUNT: usize = 4096;
+
+/// The CR3 bits holding the process-context identifier.
+const CR3_PCID_MASK: usize = 0xfff;
+
+/// The CR3 bit that keeps the TLB entries of the new
+/// process-context identifier when loading CR3.
+const CR3_NO_FLUSH: usize = 1 << 63;
+
+/// Whether process-context identifiers are enabled.
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The process-context identifiers of the page tables.
+///
+/// Identifier 0 belongs to the boot page table and is shared
+/// by every page table created while all others are taken.
+static PCIDS: Mutex<PcidAllocator> = Mutex::new(PcidAllocator::new());
+
+/// The generation of the shared kernel mappings.
+///
+/// Bumped whenever a higher half mapping goes away or loses
+/// permissions, as the TLB entries kept for inactive
+/// identifiers may still hold the old mapping.
+static KERNEL_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The last GiB of the address space, used by `read_phys_early`.
+const EARLY_WINDOW: VirtualAddress = 0xffff_ffff_c000_0000;
+
+extern "C" {
+    /// The bottom of the boot stack from `boot.asm`.
+    static stack_bottom: u8;
+
+    /// The PD of the early window from `paging.asm`.
+    static mut early_window_table: u8;
+}
+
+/// Initializes paging.
+///
+/// Enables process-context identifiers if available. The page table
+/// set up by `paging.asm` is the active table of the memory controller.
+pub fn init() {
+    if cpu::has_pcid() {
+        unsafe { cpu::enable_pcid() };
+        PCID_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Allocates a process-context identifier.
+///
+/// Returns the shared identifier 0 if identifiers are disabled
+/// or all are taken by live page tables, so the page table
+/// gets its TLB entries flushed on every switch.
+fn alloc_pcid() -> u16 {
+    if !PCID_ENABLED.load(Ordering::SeqCst) {
+        return 0;
+    }
+    interrupts::without_interrupts(|| PCIDS.lock().alloc()).unwrap_or(0)
+}
+
+/// The `PcidAllocator` type.
+///
+/// Hands out process-context identifiers and keeps the
+/// ones of dropped page tables in a free list for reuse.
+struct PcidAllocator {
+    /// The next identifier never handed out.
+    next: usize,
+
+    /// The identifiers given back.
+    free: [u16; PCID_COUNT],
+
+    /// The number of identifiers given back.
+    free_count: usize,
+}
+
+/// The `PcidAllocator` implementation.
+impl PcidAllocator {
+    /// Constructs a new `PcidAllocator`.
+    const fn new() -> PcidAllocator {
+        PcidAllocator {
+            next: 1,
+            free: [0; PCID_COUNT],
+            free_count: 0,
+        }
+    }
+
+    /// Allocates an identifier.
+    ///
+    /// Reuses the identifier given back last.
+    fn alloc(&mut self) -> Option<u16> {
+        if self.free_count > 0 {
+            self.free_count -= 1;
+            return Some(self.free[self.free_count]);
+        }
+        if self.next == PCID_COUNT {
+            return None;
+        }
+        self.next += 1;
+        Some((self.next - 1) as u16)
+    }
+
+    /// Gives an identifier back.
+    ///
+    /// The TLB may still hold entries of the identifier, so the
+    /// next page table using it has to be flushed when switched to.
+    fn free(&mut self, pcid: u16) {
+        assert!(pcid != 0 && self.free_count < PCID_COUNT);
+        self.free[self.free_count] = pcid;
+        self.free_count += 1;
+    }
+}
+
+/// Tests if the page lies in the first GiB of the kernel windows.
+///
+/// Holds scratch pages, which `Mapper::map_to` invalidates every
+/// time they get mapped, as the TLB entries kept for inactive
+/// identifiers may still hold an earlier mapping.
+fn is_scratch_page(page: Page) -> bool {
+    let scratch = Page::get_page_at_address(KERNEL_WINDOWS);
+    page.p4_index() == scratch.p4_index() && page.p3_index() == scratch.p3_index()
+}
+
+/// Notes that a mapping of the page went away or lost permissions.
+///
+/// Higher half mappings are shared by every page table, so the
+/// other identifiers have to drop their TLB entries as well.
+fn kernel_mapping_revoked(page: Page) {
+    if page.p4_index() >= KERNEL_P4_START && !is_scratch_page(page) {
+        KERNEL_GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the CR3 register.
+fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3
+}
+
+/// Reads a value from physical memory before the kernel is remapped.
+///
+/// The two 2 MiB pages around the address are mapped read-only into
+/// the early window of the boot page table for the duration of the
+/// read, so the value may lie anywhere in physical memory.
+///
+/// # Safety
+///
+/// The boot page table must still be active and the
+/// address must hold a valid value of the type.
+pub unsafe fn read_phys_early<T: Copy>(addr: PhysicalAddress) -> T {
+    let huge_size = PAGE_SIZE * HUGE_2M_PAGES;
+    let base = addr & !(huge_size - 1);
+    assert!(addr + mem::size_of::<T>() <= base + 2 * huge_size);
+
+    let window = &mut *(ptr::addr_of_mut!(early_window_table) as *mut Table<Level2>);
+    let window_frame = Frame::get_frame_for_address(virt_to_phys(window as *mut _ as usize));
+    let p3 = (*LEVEL4_TABLE)
+        .next_table_mut(ENTRY_COUNT - 1)
+        .expect("Kernel not mapped");
+    p3[ENTRY_COUNT - 1]
+        .set_flags(window_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE)
+        .unwrap();
+    for index in 0..2 {
+        let frame = Frame::get_frame_for_address(base + index * huge_size);
+        window[index].set_flags(frame, EntryFlags::PRESENT | EntryFlags::HUGE_PAGE).unwrap();
+    }
+    asm!("mov cr3, {}", in(reg) read_cr3(), options(nostack, preserves_flags));
+
+    let value = ptr::read_unaligned((EARLY_WINDOW + addr - base) as *const T);
+
+    window.zero_fill();
+    p3[ENTRY_COUNT - 1].mark_unused();
+    asm!("mov cr3, {}", in(reg) read_cr3(), options(nostack, preserves_flags));
+    value
+}
+
+/// Remaps the kernel into a new page table and switches to it.
+///
+/// Every higher half kernel section is mapped with the permissions
+/// its ELF flags call for. The VGA buffer, the multiboot2 data and
+/// the specified physical regions are mapped at `KERNEL_OFFSET`
+/// as non-executable data, and the P3 table of the kernel windows
+/// is allocated. Everything else from the initial mapping goes
+/// away, including the boot trampoline.
+///
+/// The page below the boot stack is left unmapped, so a stack
+/// overflow faults instead of corrupting the initial page tables,
+/// which are unused from then on.
+pub fn remap_kernel<A>(active_table: &mut ActivePageTable,
+                       allocator: &mut A,
+                       mb2_info: &BootInformation,
+                       multiboot2_addr: VirtualAddress,
+                       regions: &[(PhysicalAddress, PhysicalAddress)])
+    where A: FrameAllocator
+{
+    // Honor the NO_EXECUTE and read-only bits from here on
+    unsafe {
+        cpu::enable_nxe();
+        cpu::enable_write_protect();
+    }
+
+    let mut temporary_page = TemporaryPage::new(Page::get_page_at_address(TEMPORARY_PAGE),
+                                                allocator);
+    let mut new_table = {
+        let frame = allocator.alloc_frame().expect("Out of memory");
+        InactivePageTable::new(frame, active_table, &mut temporary_page)
+    };
+
+    let guard_page = stack_guard_page();
+    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        let elf_sections = mb2_info.elf_sections_tag().expect("ELF sections tag required");
+
+        // Map the kernel sections
+        for section in elf_sections.sections() {
+            if section.flags & ELF_SECTION_ALLOCATED == 0 || section.size == 0 ||
+               (section.addr as usize) < KERNEL_OFFSET {
+                continue;
+            }
+            assert!((section.addr as usize).is_multiple_of(PAGE_SIZE),
+                    "Kernel sections need to be page aligned");
+            let mut flags = EntryFlags::NO_EXECUTE;
+            if section.flags & ELF_SECTION_WRITABLE != 0 {
+                flags.insert(EntryFlags::WRITABLE);
+            }
+            if section.flags & ELF_SECTION_EXECUTABLE != 0 {
+                flags.remove(EntryFlags::NO_EXECUTE);
+            }
+            let start = section.addr as usize;
+            let end = start + section.size as usize;
+            if start <= guard_page && guard_page < end {
+                map_kernel_unmapped(mapper, start, guard_page, flags, allocator);
+                map_kernel_unmapped(mapper, guard_page + PAGE_SIZE, end, flags, allocator);
+            } else {
+                map_kernel_unmapped(mapper, start, end, flags, allocator);
+            }
+        }
+
+        // Map the VGA buffer, the multiboot2 data and the extra regions
+        map_kernel_unmapped(mapper,
+                            phys_to_virt(VGA_BUFFER),
+                            phys_to_virt(VGA_BUFFER) + VGA_BUFFER_SIZE,
+                            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                            allocator);
+        map_kernel_unmapped(mapper,
+                            multiboot2_addr,
+                            multiboot2_addr + mb2_info.total_size as usize,
+                            EntryFlags::NO_EXECUTE,
+                            allocator);
+        for &(start, end) in regions {
+            let virt_start = phys_to_virt(start);
+            map_kernel_unmapped(mapper,
+                                virt_start,
+                                virt_start + (end - start),
+                                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                                allocator);
+        }
+
+        // Allocate the P3 table of the kernel windows up front
+        let windows = Page::get_page_at_address(KERNEL_WINDOWS);
+        mapper.p4_mut()
+            .create_next_table(windows.p4_index(), allocator)
+            .expect("Failed to remap the kernel");
+    });
+
+    // The boot table isn't handed back, as its lower half is empty,
+    // and the identifier of the new one stays in use while active
+    load_table(&new_table);
+    mem::forget(new_table);
+}
+
+/// Loads the specified page table into CR3.
+///
+/// Keeps its TLB entries under the conditions described at `switch`.
+fn load_table(table: &InactivePageTable) {
+    let mut cr3 = table.p4_frame.get_start_address() | table.pcid as usize;
+    if table.pcid != 0 && !table.needs_flush &&
+       table.generation == KERNEL_GENERATION.load(Ordering::SeqCst) {
+        cr3 |= CR3_NO_FLUSH;
+    }
+    unsafe {
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    }
+}
+
+/// Gets the address of the guard page below the boot stack.
+pub fn stack_guard_page() -> VirtualAddress {
+    unsafe { &stack_bottom as *const u8 as usize - PAGE_SIZE }
+}
+
+/// Maps every page overlapping the higher half range that is not
+/// mapped yet to the frame `KERNEL_OFFSET` below it.
+fn map_kernel_unmapped<A>(mapper: &mut Mapper,
+                          start: VirtualAddress,
+                          end: VirtualAddress,
+                          flags: EntryFlags,
+                          allocator: &mut A)
+    where A: FrameAllocator
+{
+    for page in Page::range_containing(start, end) {
+        if mapper.translate_page(page).is_none() {
+            let frame = Frame::get_frame_for_address(virt_to_phys(page.address()));
+            mapper.map_to(page, frame, flags, allocator)
+                .expect("Failed to remap the kernel");
+        }
+    }
+}
+
 /// The `ActivePageTable` type.
 pub struct ActivePageTable {
     /// The mapper.
     mapper: Mapper,
+
+    /// The virtual regions of the lower half.
+    address_space: AddressSpace,
 }
 
 /// The `Deref` implementation for `ActivePageTable`.
@@ -52,10 +397,64 @@ impl DerefMut for ActivePageTable {
 /// The `ActivePageTable` implementation.
 impl ActivePageTable {
     /// Constructs a new `ActivePageTable`.
-    unsafe fn new() -> ActivePageTable {
-        ActivePageTable { mapper: Mapper::new() }
+    ///
+    /// # Safety
+    ///
+    /// Only one may exist at a time, as it
+    /// accesses the tables through the recursive mapping.
+    pub const unsafe fn new() -> ActivePageTable {
+        ActivePageTable {
+            mapper: Mapper::new(),
+            address_space: AddressSpace::new(USER_START, USER_END),
+        }
+    }
+
+    /// Gets the virtual regions of the lower half.
+    #[allow(dead_code)]
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// Gets the virtual regions of the lower half as mutable.
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    /// Switches to the specified page table and its address space.
+    ///
+    /// Returns the previously active page table. With process-context
+    /// identifiers, the TLB entries of the new table are kept unless
+    /// it was modified while inactive, the kernel mappings changed
+    /// since it was last active, or it shares identifier 0.
+    #[allow(dead_code)]
+    pub fn switch(&mut self, mut new_table: InactivePageTable) -> InactivePageTable {
+        let cr3 = read_cr3();
+        let address_space = mem::replace(&mut new_table.address_space,
+                                         AddressSpace::new(USER_START, USER_END));
+        let old_table = InactivePageTable {
+            p4_frame: Frame::get_frame_for_address(cr3),
+            pcid: if PCID_ENABLED.load(Ordering::SeqCst) {
+                (cr3 & CR3_PCID_MASK) as u16
+            } else {
+                0
+            },
+            needs_flush: false,
+            generation: KERNEL_GENERATION.load(Ordering::SeqCst),
+            address_space: mem::replace(&mut self.address_space, address_space),
+        };
+
+        // The identifier of the new table stays in use while active
+        load_table(&new_table);
+        mem::forget(new_table);
+        old_table
     }
 
+    /// Runs the closure with the specified page table
+    /// mapped in place of the active one.
+    ///
+    /// The closure modifies the inactive table through the
+    /// recursive mapping, so its TLB entries are flushed
+    /// the next time it becomes active.
     pub fn with<F>(&mut self,
                    table: &mut InactivePageTable,
                    temporary_page: &mut temp_page::TemporaryPage,
@@ -64,24 +463,175 @@ impl ActivePageTable {
     {
         let flush_tlb = || unsafe {
             // Invalidate the translation lookaside buffer
-            let cr3: usize;
-            asm!("mov %cr3, $0" : "=r" (cr3));
-            asm!("mov $0, %cr3" :: "r" (cr3) : "memory");
+            asm!("mov cr3, {}", in(reg) read_cr3(), options(nostack, preserves_flags));
         };
         {
-            let backup = Frame::get_frame_for_address(unsafe {
-                let cr3: usize;
-                asm!("mov %cr3, $0" : "=r" (cr3));
-                cr3
-            });
+            let backup = Frame::get_frame_for_address(read_cr3());
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
-            self.p4_mut()[511].set_flags(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[RECURSIVE_INDEX]
+                .set_flags(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE)
+                .unwrap();
             flush_tlb();
             f(self);
-            p4_table[511].set_flags(backup, PRESENT | WRITABLE);
+            p4_table[RECURSIVE_INDEX]
+                .set_flags(backup, EntryFlags::PRESENT | EntryFlags::WRITABLE)
+                .unwrap();
             flush_tlb();
         }
         temporary_page.unmap(self);
+        table.needs_flush = true;
+    }
+
+    /// Clones the page table and its address space
+    /// for a `fork`-style address space.
+    ///
+    /// Only the lower half is copied, the higher half P4 entries
+    /// point to the same kernel tables in both. The pages of the
+    /// anonymous regions of the address space are shared
+    /// by both tables. Writable ones become read-only copy-on-write
+    /// pages, and huge pages inside the regions are split first.
+    /// Tables and huge pages covering no anonymous region are
+    /// shared as a whole.
+    ///
+    /// A partial copy is leaked if the frame allocator runs out.
+    #[allow(dead_code)]
+    pub fn clone_cow<A>(&mut self,
+                        frame_refs: &mut FrameRefCounts,
+                        allocator: &mut A)
+                        -> Result<InactivePageTable, MapError>
+        where A: FrameAllocator
+    {
+        let mut temporary_page = TemporaryPage::new(Page::get_page_at_address(TEMPORARY_PAGE),
+                                                    allocator);
+        let frame = allocator.alloc_frame().ok_or(MapError::OutOfFrames)?;
+        let mut new_table = InactivePageTable::new(frame.clone(), self, &mut temporary_page);
+        new_table.address_space = self.address_space;
+        let result = {
+            let space = self.address_space;
+            let mut cloner = TableCloner {
+                active_table: self,
+                temporary_page: &mut temporary_page,
+                space: &space,
+                frame_refs,
+                allocator,
+            };
+            cloner.clone_table(LEVEL4_TABLE as usize, 4, 0, &frame)
+        };
+        temporary_page.free(allocator);
+
+        // Drop the writable TLB entries of the shared pages
+        unsafe {
+            asm!("mov cr3, {}", in(reg) read_cr3(), options(nostack, preserves_flags));
+        }
+        result.map(|()| new_table)
+    }
+}
+
+/// The `TableCloner` type.
+///
+/// Copies the tables of the active page table into a new one.
+struct TableCloner<'a, A: 'a> {
+    /// The active page table.
+    active_table: &'a mut ActivePageTable,
+
+    /// The page used to write the new tables.
+    temporary_page: &'a mut TemporaryPage,
+
+    /// The address space holding the regions to share.
+    space: &'a AddressSpace,
+
+    /// The frame reference counts.
+    frame_refs: &'a mut FrameRefCounts,
+
+    /// The frame allocator.
+    allocator: &'a mut A,
+}
+
+/// The `TableCloner` implementation.
+impl<'a, A> TableCloner<'a, A>
+    where A: FrameAllocator
+{
+    /// Copies the entries of a table into the specified zeroed frame.
+    ///
+    /// The table is accessed through its recursive address
+    /// and maps the virtual range starting at `start`.
+    fn clone_table(&mut self,
+                   table_addr: VirtualAddress,
+                   level: usize,
+                   start: VirtualAddress,
+                   child: &Frame)
+                   -> Result<(), MapError> {
+        let span = PAGE_SIZE << (9 * (level - 1));
+        for index in 0..ENTRY_COUNT {
+            // Leave the recursive entry set up by `InactivePageTable::new` alone
+            if level == 4 && index == RECURSIVE_INDEX {
+                continue;
+            }
+            let table = unsafe { &mut *(table_addr as *mut Table<Level1>) };
+            let (mut frame, mut flags) = match table[index].frame() {
+                Some(frame) => (frame, table[index].flags()),
+                None => continue,
+            };
+            // Share the kernel tables as they are
+            let entry_start = canonical(start + index * span);
+            let kernel_entry = level == 4 && index >= KERNEL_P4_START;
+            if !kernel_entry && self.covers_anonymous(entry_start, span) {
+                // Split huge pages, so their frames can be shared one by one
+                if level > 1 && flags.contains(EntryFlags::HUGE_PAGE) {
+                    self.active_table
+                        .split_huge_page(Page::get_page_at_address(entry_start), self.allocator)?;
+                    frame = table[index].frame().unwrap();
+                    flags = table[index].flags();
+                }
+                if level == 1 {
+                    // Share the page copy-on-write
+                    if flags.contains(EntryFlags::WRITABLE) {
+                        flags.remove(EntryFlags::WRITABLE);
+                        flags.insert(EntryFlags::COPY_ON_WRITE);
+                        table[index].set_flags(frame.clone(), flags)?;
+                    }
+                    self.frame_refs.share(&frame);
+                } else {
+                    // Copy the next table
+                    let next_frame = self.allocator.alloc_frame().ok_or(MapError::OutOfFrames)?;
+                    self.temporary_page
+                        .map_table_frame(next_frame.clone(), self.active_table)
+                        .zero_fill();
+                    self.temporary_page.unmap(self.active_table);
+                    self.clone_table(canonical((table_addr << 9) | (index << 12)),
+                                     level - 1,
+                                     entry_start,
+                                     &next_frame)?;
+                    frame = next_frame;
+                }
+            }
+
+            let result = self.temporary_page
+                .map_table_frame(child.clone(), self.active_table)[index]
+                .set_flags(frame, flags);
+            self.temporary_page.unmap(self.active_table);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Tests if the range overlaps an anonymous region.
+    fn covers_anonymous(&self, start: VirtualAddress, size: usize) -> bool {
+        self.space
+            .iter()
+            .any(|region| {
+                region.backing == Backing::Anonymous && region.start < start + size &&
+                start < region.end()
+            })
+    }
+}
+
+/// Sign-extends bit 47 of an address into the upper bits.
+fn canonical(addr: VirtualAddress) -> VirtualAddress {
+    if addr & (1 << 47) != 0 {
+        addr | 0xffff_0000_0000_0000
+    } else {
+        addr
     }
 }
 
@@ -89,6 +639,18 @@ impl ActivePageTable {
 pub struct InactivePageTable {
     /// The level 4 page frame.
     p4_frame: Frame,
+
+    /// The process-context identifier.
+    pcid: u16,
+
+    /// Whether stale TLB entries may exist for the identifier.
+    needs_flush: bool,
+
+    /// The generation of the kernel mappings when it was last active.
+    generation: usize,
+
+    /// The virtual regions of the lower half.
+    address_space: AddressSpace,
 }
 
 /// The `InactivePageTable` implementation.
@@ -101,15 +663,51 @@ impl InactivePageTable {
         {
             let table = temporary_page.map_table_frame(frame.clone(), active_table);
             table.zero_fill();
-            table[511].set_flags(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_INDEX]
+                .set_flags(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE)
+                .unwrap();
         }
         temporary_page.unmap(active_table);
-        InactivePageTable { p4_frame: frame }
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: alloc_pcid(),
+            needs_flush: true,
+            generation: KERNEL_GENERATION.load(Ordering::SeqCst),
+            address_space: AddressSpace::new(USER_START, USER_END),
+        }
+    }
+
+    /// Gets the process-context identifier.
+    #[allow(dead_code)]
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    /// Gets the virtual regions of the lower half.
+    #[allow(dead_code)]
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// Gets the virtual regions of the lower half as mutable.
+    #[allow(dead_code)]
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+}
+
+/// The `Drop` implementation for `InactivePageTable`.
+impl Drop for InactivePageTable {
+    /// Gives the process-context identifier back for reuse.
+    fn drop(&mut self) {
+        if self.pcid != 0 {
+            interrupts::without_interrupts(|| PCIDS.lock().free(self.pcid));
+        }
     }
 }
 
 /// The `Page` type.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
     /// The index.
     index: usize,
@@ -127,6 +725,29 @@ impl Page {
         self.index * PAGE_SIZE
     }
 
+    /// Gets the range of pages from `start` up to, but excluding, `end`.
+    pub fn range(start: Page, end: Page) -> PageRange {
+        PageRange {
+            start,
+            end,
+        }
+    }
+
+    /// Gets the range of pages overlapping the
+    /// addresses from `start` up to, but excluding, `end`.
+    pub fn range_containing(start: VirtualAddress, end: VirtualAddress) -> PageRange {
+        let start_page = Page::get_page_at_address(start);
+        if end <= start {
+            return Page::range(start_page, start_page);
+        }
+        Page::range(start_page, Page::get_page_at_address(end - 1).next())
+    }
+
+    /// Gets the page after this one.
+    pub fn next(&self) -> Page {
+        Page { index: self.index + 1 }
+    }
+
     /// Gets the P4 index.
     fn p4_index(&self) -> usize {
         (self.index >> 27) & 0o777
@@ -147,3 +768,46 @@ impl Page {
         self.index & 0o777
     }
 }
+
+/// The `PageRange` type.
+///
+/// Iterates over a range of consecutive pages.
+#[derive(Debug, Copy, Clone)]
+pub struct PageRange {
+    /// The first page.
+    start: Page,
+
+    /// The page after the last page.
+    end: Page,
+}
+
+/// The `PageRange` implementation.
+impl PageRange {
+    /// Gets the number of pages left.
+    pub fn len(&self) -> usize {
+        self.end.index.saturating_sub(self.start.index)
+    }
+
+    /// Tests if no pages are left.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The `Iterator` implementation for `PageRange`.
+impl Iterator for PageRange {
+    /// The item.
+    type Item = Page;
+
+    /// Gets the next page.
+    fn next(&mut self) -> Option<Page> {
+        if self.start < self.end {
+            let page = self.start;
+            self.start = page.next();
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 use memory::paging::entry::*;
-use memory::paging::ENTRY_COUNT;
+use memory::paging::{ENTRY_COUNT, MapError, canonical};
 use memory::FrameAllocator;
 
-/// The level 4 table.
-pub const LEVEL4_TABLE: *mut Table<Level4> = 0xfffffffffffff000 as *mut _;
+/// The level 4 table, reached through the recursive entry four times.
+pub const LEVEL4_TABLE: *mut Table<Level4> = 0xffffff7fbfdfe000 as *mut _;
 
 /// The `TableLevel` trait.
 pub trait TableLevel {}
@@ -106,6 +106,11 @@ impl<L> Table<L>
             entry.mark_unused();
         }
     }
+
+    /// Tests if every entry is unused.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 /// The `Table` implementation.
@@ -116,24 +121,27 @@ impl<L> Table<L>
     pub fn create_next_table<A>(&mut self,
                                 index: usize,
                                 allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
+                                -> Result<&mut Table<L::NextLevel>, MapError>
         where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(HUGE_PAGE));
-            let frame = allocator.alloc_frame().unwrap();
-            self.entries[index].set_flags(frame, PRESENT | WRITABLE);
+            if self.entries[index].flags().contains(EntryFlags::HUGE_PAGE) {
+                return Err(MapError::HugePageInTheWay);
+            }
+            let frame = allocator.alloc_frame().ok_or(MapError::OutOfFrames)?;
+            self.entries[index].set_flags(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE)?;
             self.next_table_mut(index).unwrap().zero_fill();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
     }
 
     /// Gets the address of the next table.
     pub fn next_table_addr(&self, index: usize) -> Option<usize> {
         let entry_flags = self[index].flags();
-        if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+        if entry_flags.contains(EntryFlags::PRESENT) &&
+           !entry_flags.contains(EntryFlags::HUGE_PAGE) {
             let table_addr = self as *const _ as usize;
-            Some((table_addr << 9) | (index << 12))
+            Some(canonical((table_addr << 9) | (index << 12)))
         } else {
             None
         }
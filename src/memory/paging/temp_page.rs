@@ -56,21 +56,35 @@ impl TemporaryPage {
         where A: FrameAllocator
     {
         TemporaryPage {
-            page: page,
+            page,
             allocator: TinyAllocator::new(allocator),
         }
     }
     /// Maps the temporary page.
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddress {
-        use super::entry::WRITABLE;
+        use super::entry::EntryFlags;
         assert!(active_table.translate_page(self.page).is_none());
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
+        active_table.map_to(self.page, frame, EntryFlags::WRITABLE, &mut self.allocator)
+            .expect("Failed to map the temporary page");
         self.page.address()
     }
 
     /// Unmaps the temporary page.
+    ///
+    /// The mapped frame stays with its owner, while the
+    /// page tables go back to the tiny allocator.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator);
+        active_table.unmap_keep_frame(self.page, &mut self.allocator);
+    }
+
+    /// Returns the frames of the tiny allocator to the specified allocator.
+    pub fn free<A>(self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let mut tiny_allocator = self.allocator;
+        while let Some(frame) = tiny_allocator.alloc_frame() {
+            allocator.dealloc_frame(frame);
+        }
     }
 
     /// Maps a temporary page to the specified frame.
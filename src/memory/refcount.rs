@@ -0,0 +1,128 @@
+use core::{cmp, slice};
+use memory::{Frame, MemoryMap, phys_to_virt, virt_to_phys};
+use memory::paging::PhysicalAddress;
+
+/// The `FrameRefCounts` type.
+///
+/// Counts the page table entries sharing a frame.
+/// Frames with a single owner keep a count of zero.
+pub struct FrameRefCounts {
+    /// The counts, indexed by frame.
+    counts: &'static mut [u16],
+}
+
+/// The `FrameRefCounts` implementation.
+impl FrameRefCounts {
+    /// Constructs a new `FrameRefCounts` covering no frames.
+    pub const fn empty() -> FrameRefCounts {
+        FrameRefCounts { counts: &mut [] }
+    }
+
+    /// Constructs a new `FrameRefCounts` covering every frame of RAM.
+    ///
+    /// The counts are placed in a usable region of the memory map.
+    pub fn new(map: &mut MemoryMap) -> FrameRefCounts {
+        let frame_count = Frame::get_frame_for_address(map.ram_end() - 1).index + 1;
+        let size = frame_count * 2;
+
+        // Find a place for the counts
+        let start = map.find_gap(size).expect("No room for the frame reference counts");
+        map.reserve(start, start + size, "Frame reference counts")
+            .expect("Failed to reserve the frame reference counts");
+        let counts = unsafe {
+            slice::from_raw_parts_mut(phys_to_virt(start) as *mut u16, frame_count)
+        };
+        for count in counts.iter_mut() {
+            *count = 0;
+        }
+        FrameRefCounts { counts }
+    }
+
+    /// Gets the physical region occupied by the counts.
+    pub fn region(&self) -> (PhysicalAddress, PhysicalAddress) {
+        let start = virt_to_phys(self.counts.as_ptr() as usize);
+        (start, start + self.counts.len() * 2)
+    }
+
+    /// Gets the number of owners of a frame.
+    pub fn count(&self, frame: &Frame) -> usize {
+        cmp::max(self.counts[frame.index], 1) as usize
+    }
+
+    /// Adds an owner to a frame.
+    pub fn share(&mut self, frame: &Frame) {
+        let count = &mut self.counts[frame.index];
+        assert!(*count < u16::MAX, "Frame shared too often");
+        *count = cmp::max(*count, 1) + 1;
+    }
+
+    /// Removes an owner from a frame.
+    ///
+    /// Returns `true` if it was the last owner,
+    /// in which case the frame may be deallocated.
+    pub fn release(&mut self, frame: &Frame) -> bool {
+        let count = &mut self.counts[frame.index];
+        if *count <= 2 {
+            let last = *count <= 1;
+            *count = 0;
+            return last;
+        }
+        *count -= 1;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds counts covering the specified number of frames.
+    fn refs(frame_count: usize) -> FrameRefCounts {
+        FrameRefCounts { counts: Box::leak(vec![0; frame_count].into_boxed_slice()) }
+    }
+
+    #[test]
+    fn single_owner_is_released() {
+        let mut refs = refs(4);
+        let frame = Frame { index: 2 };
+        assert_eq!(refs.count(&frame), 1);
+        assert!(refs.release(&frame));
+        assert_eq!(refs.count(&frame), 1);
+    }
+
+    #[test]
+    fn shared_frame_is_released_by_the_last_owner() {
+        let mut refs = refs(4);
+        let frame = Frame { index: 1 };
+        refs.share(&frame);
+        refs.share(&frame);
+        assert_eq!(refs.count(&frame), 3);
+        assert!(!refs.release(&frame));
+        assert_eq!(refs.count(&frame), 2);
+        assert!(!refs.release(&frame));
+        assert_eq!(refs.count(&frame), 1);
+        assert!(refs.release(&frame));
+        assert_eq!(refs.counts[1], 0);
+    }
+
+    #[test]
+    fn counts_are_kept_per_frame() {
+        let mut refs = refs(4);
+        refs.share(&Frame { index: 0 });
+        assert_eq!(refs.count(&Frame { index: 0 }), 2);
+        assert_eq!(refs.count(&Frame { index: 3 }), 1);
+        assert!(refs.release(&Frame { index: 3 }));
+        assert_eq!(refs.count(&Frame { index: 0 }), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Frame shared too often")]
+    fn share_panics_when_the_count_overflows() {
+        let mut refs = refs(1);
+        let frame = Frame { index: 0 };
+        refs.counts[0] = u16::MAX - 1;
+        refs.share(&frame);
+        assert_eq!(refs.count(&frame), u16::MAX as usize);
+        refs.share(&frame);
+    }
+}
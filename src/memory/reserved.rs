@@ -0,0 +1,348 @@
+use core::{cmp, slice};
+use memory::{PAGE_SIZE, virt_to_phys};
+use memory::paging::{self, PhysicalAddress};
+
+/// The maximum number of reserved regions.
+pub const MAX_RESERVED_REGIONS: usize = 32;
+
+/// The end of the legacy BIOS and device memory.
+const LOW_MEMORY_END: PhysicalAddress = 0x100000;
+
+/// The address of the VGA text buffer.
+pub const VGA_BUFFER: PhysicalAddress = 0xB8000;
+
+/// The size of the VGA text buffer.
+pub const VGA_BUFFER_SIZE: usize = 80 * 25 * 2;
+
+/// The multiboot2 end tag type.
+const TAG_END: u32 = 0;
+
+/// The multiboot2 module tag type.
+const TAG_MODULE: u32 = 3;
+
+/// The multiboot2 tag type holding an ACPI 1.0 RSDP.
+const TAG_ACPI_OLD_RSDP: u32 = 14;
+
+/// The multiboot2 tag type holding an ACPI 2.0 RSDP.
+const TAG_ACPI_NEW_RSDP: u32 = 15;
+
+/// The size of an ACPI table header.
+const ACPI_HEADER_SIZE: usize = 36;
+
+/// The signature of the fixed ACPI description table.
+const FADT_SIGNATURE: [u8; 4] = *b"FACP";
+
+/// The FADT offset of the 32-bit FACS address.
+const FADT_FIRMWARE_CTRL: usize = 36;
+
+/// The FADT offset of the 32-bit DSDT address.
+const FADT_DSDT: usize = 40;
+
+/// The FADT offset of the 64-bit FACS address.
+const FADT_X_FIRMWARE_CTRL: usize = 132;
+
+/// The FADT offset of the 64-bit DSDT address.
+const FADT_X_DSDT: usize = 140;
+
+extern "C" {
+    /// The initial PML4 from `paging.asm`.
+    static page_map_level4_table: u8;
+
+    /// The initial PDP from `paging.asm`.
+    static page_directory_pointer_table: u8;
+
+    /// The initial PD from `paging.asm`.
+    static page_directory_table: u8;
+}
+
+/// The `ReservedError` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReservedError {
+    /// The region could not be merged and no slot is left.
+    TooManyRegions,
+}
+
+/// Collects the regions that are in use at boot into `regions`.
+///
+/// Covers low memory, the VGA buffer, the kernel, the multiboot2
+/// data and modules, the ACPI tables and the initial page tables.
+/// The multiboot2 data is read through its virtual address.
+pub fn collect(regions: &mut ReservedRegions,
+               multiboot2_addr: usize,
+               kernel_start: PhysicalAddress,
+               kernel_end: PhysicalAddress)
+               -> Result<(), ReservedError> {
+    regions.add(0, LOW_MEMORY_END, "Low memory")?;
+    regions.add(VGA_BUFFER, VGA_BUFFER + VGA_BUFFER_SIZE, "VGA buffer")?;
+    regions.add(kernel_start, kernel_end, "Kernel")?;
+
+    // Reserve the initial page tables
+    unsafe {
+        let tables = [&page_map_level4_table as *const u8 as usize,
+                      &page_directory_pointer_table as *const u8 as usize,
+                      &page_directory_table as *const u8 as usize];
+        for &table in tables.iter() {
+            let table = virt_to_phys(table);
+            regions.add(table, table + PAGE_SIZE, "Initial page table")?;
+        }
+    }
+
+    // Reserve the multiboot2 data and whatever its tags point to
+    let total_size = unsafe { *(multiboot2_addr as *const u32) } as usize;
+    let multiboot2_start = virt_to_phys(multiboot2_addr);
+    regions.add(multiboot2_start, multiboot2_start + total_size, "Multiboot2 data")?;
+    let mut tag = multiboot2_addr + 8;
+    loop {
+        let (typ, size) = unsafe { (*(tag as *const u32), *((tag + 4) as *const u32) as usize) };
+        match typ {
+            TAG_END => break,
+            TAG_MODULE => {
+                let (start, end) = unsafe {
+                    (*((tag + 8) as *const u32) as usize, *((tag + 12) as *const u32) as usize)
+                };
+                regions.add(start, end, "Multiboot2 module")?;
+            }
+            TAG_ACPI_OLD_RSDP | TAG_ACPI_NEW_RSDP => {
+                reserve_acpi_tables(regions, tag + 8)?;
+            }
+            _ => (),
+        }
+        tag += (size + 7) & !7;
+    }
+    Ok(())
+}
+
+/// Reserves the ACPI root table and every table it points to,
+/// including the DSDT and the FACS behind the FADT.
+///
+/// The tables are read through the early window,
+/// as they may lie anywhere in physical memory.
+fn reserve_acpi_tables(regions: &mut ReservedRegions, rsdp: usize) -> Result<(), ReservedError> {
+    let (root, entry_size) = unsafe {
+        let revision = *((rsdp + 15) as *const u8);
+        if revision >= 2 {
+            (*((rsdp + 24) as *const u64) as usize, 8)
+        } else {
+            (*((rsdp + 16) as *const u32) as usize, 4)
+        }
+    };
+    let root_length = reserve_acpi_table(regions, root)?;
+    let mut entry = root + ACPI_HEADER_SIZE;
+    while entry + entry_size <= root + root_length {
+        let table = unsafe {
+            if entry_size == 8 {
+                paging::read_phys_early::<u64>(entry) as usize
+            } else {
+                paging::read_phys_early::<u32>(entry) as usize
+            }
+        };
+        let length = reserve_acpi_table(regions, table)?;
+        if unsafe { paging::read_phys_early::<[u8; 4]>(table) } == FADT_SIGNATURE {
+            reserve_fadt_tables(regions, table, length)?;
+        }
+        entry += entry_size;
+    }
+    Ok(())
+}
+
+/// Reserves the FACS and the DSDT the FADT points to.
+///
+/// The 64-bit addresses of ACPI 2.0 take precedence over the
+/// 32-bit ones, as far as the FADT is long enough to hold them.
+fn reserve_fadt_tables(regions: &mut ReservedRegions,
+                       fadt: PhysicalAddress,
+                       length: usize)
+                       -> Result<(), ReservedError> {
+    let pointers = [(FADT_FIRMWARE_CTRL, FADT_X_FIRMWARE_CTRL), (FADT_DSDT, FADT_X_DSDT)];
+    for &(offset, x_offset) in pointers.iter() {
+        let mut table = unsafe { paging::read_phys_early::<u32>(fadt + offset) } as usize;
+        if x_offset + 8 <= length {
+            let x_table = unsafe { paging::read_phys_early::<u64>(fadt + x_offset) } as usize;
+            if x_table != 0 {
+                table = x_table;
+            }
+        }
+        if table != 0 {
+            reserve_acpi_table(regions, table)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reserves a single ACPI table and returns its length.
+fn reserve_acpi_table(regions: &mut ReservedRegions,
+                      table: PhysicalAddress)
+                      -> Result<usize, ReservedError> {
+    let length = unsafe { paging::read_phys_early::<u32>(table + 4) } as usize;
+    regions.add(table, table + length, "ACPI table")?;
+    Ok(length)
+}
+
+/// The `ReservedRegion` type.
+///
+/// Represents a physical region no frame allocator may hand out.
+#[derive(Debug, Copy, Clone)]
+pub struct ReservedRegion {
+    /// The start address.
+    pub start: PhysicalAddress,
+
+    /// The end address, exclusive.
+    pub end: PhysicalAddress,
+
+    /// The name.
+    pub name: &'static str,
+}
+
+/// The `ReservedRegions` type.
+#[derive(Copy, Clone)]
+pub struct ReservedRegions {
+    /// The regions.
+    regions: [ReservedRegion; MAX_RESERVED_REGIONS],
+
+    /// The number of regions.
+    count: usize,
+}
+
+/// The `ReservedRegions` implementation.
+impl ReservedRegions {
+    /// Constructs a new empty `ReservedRegions`.
+    pub const fn new() -> ReservedRegions {
+        ReservedRegions {
+            regions: [ReservedRegion {
+                start: 0,
+                end: 0,
+                name: "",
+            }; MAX_RESERVED_REGIONS],
+            count: 0,
+        }
+    }
+
+    /// Adds a region.
+    ///
+    /// Merges it with the regions it overlaps and with adjacent
+    /// regions of the same name. A merged region keeps the name
+    /// of the lowest one. Empty regions are ignored.
+    pub fn add(&mut self,
+               start: PhysicalAddress,
+               end: PhysicalAddress,
+               name: &'static str)
+               -> Result<(), ReservedError> {
+        if start >= end {
+            return Ok(());
+        }
+        let mut region = ReservedRegion {
+            start,
+            end,
+            name,
+        };
+        let mut index = 0;
+        while index < self.count {
+            let other = self.regions[index];
+            let overlaps = other.start < region.end && region.start < other.end;
+            let adjacent = other.end == region.start || region.end == other.start;
+            if !overlaps && !(adjacent && other.name == region.name) {
+                index += 1;
+                continue;
+            }
+            if other.start < region.start {
+                region.name = other.name;
+            }
+            region.start = cmp::min(region.start, other.start);
+            region.end = cmp::max(region.end, other.end);
+            self.count -= 1;
+            self.regions[index] = self.regions[self.count];
+
+            // The grown region may touch regions checked before
+            index = 0;
+        }
+        if self.count == MAX_RESERVED_REGIONS {
+            return Err(ReservedError::TooManyRegions);
+        }
+        self.regions[self.count] = region;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Gets a region overlapping the specified range.
+    pub fn overlapping(&self,
+                       start: PhysicalAddress,
+                       end: PhysicalAddress)
+                       -> Option<&ReservedRegion> {
+        self.iter().find(|region| start < region.end && region.start < end)
+    }
+
+    /// Gets an iterator over the regions.
+    pub fn iter(&self) -> slice::Iter<'_, ReservedRegion> {
+        self.regions[..self.count].iter()
+    }
+}
+
+/// The `Default` implementation for `ReservedRegions`.
+impl Default for ReservedRegions {
+    fn default() -> ReservedRegions {
+        ReservedRegions::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions(regions: &ReservedRegions) -> Vec<(PhysicalAddress, PhysicalAddress, &str)> {
+        let mut list: Vec<_> = regions.iter().map(|r| (r.start, r.end, r.name)).collect();
+        list.sort();
+        list
+    }
+
+    #[test]
+    fn add_keeps_disjoint_regions_apart() {
+        let mut list = ReservedRegions::new();
+        list.add(0x3000, 0x4000, "b").unwrap();
+        list.add(0x1000, 0x2000, "a").unwrap();
+        list.add(0x5000, 0x5000, "empty").unwrap();
+        assert_eq!(regions(&list), [(0x1000, 0x2000, "a"), (0x3000, 0x4000, "b")]);
+    }
+
+    #[test]
+    fn add_merges_overlapping_regions() {
+        let mut list = ReservedRegions::new();
+        list.add(0x1000, 0x3000, "a").unwrap();
+        list.add(0x5000, 0x6000, "b").unwrap();
+        list.add(0x2000, 0x5800, "c").unwrap();
+        assert_eq!(regions(&list), [(0x1000, 0x6000, "a")]);
+    }
+
+    #[test]
+    fn add_merges_adjacent_regions_of_the_same_name() {
+        let mut list = ReservedRegions::new();
+        list.add(0x1000, 0x2000, "a").unwrap();
+        list.add(0x2000, 0x3000, "a").unwrap();
+        list.add(0x3000, 0x4000, "b").unwrap();
+        assert_eq!(regions(&list), [(0x1000, 0x3000, "a"), (0x3000, 0x4000, "b")]);
+    }
+
+    #[test]
+    fn add_merges_regions_the_grown_region_touches() {
+        let mut list = ReservedRegions::new();
+        list.add(0x3000, 0x4000, "a").unwrap();
+        list.add(0x2000, 0x3000, "b").unwrap();
+        list.add(0x1000, 0x2800, "a").unwrap();
+        assert_eq!(regions(&list), [(0x1000, 0x4000, "a")]);
+    }
+
+    #[test]
+    fn add_fails_when_full() {
+        let mut list = ReservedRegions::new();
+        for index in 0..MAX_RESERVED_REGIONS {
+            list.add(index * 0x2000, index * 0x2000 + 0x1000, "a").unwrap();
+        }
+        let end = MAX_RESERVED_REGIONS * 0x2000;
+        assert_eq!(list.add(end, end + 0x1000, "a"), Err(ReservedError::TooManyRegions));
+        assert_eq!(list.iter().count(), MAX_RESERVED_REGIONS);
+
+        // Merging still works, as it needs no new slot
+        list.add(0x1000, 0x2000, "a").unwrap();
+        assert_eq!(list.iter().count(), MAX_RESERVED_REGIONS - 1);
+        assert!(list.overlapping(0x1800, 0x1900).is_some());
+    }
+}
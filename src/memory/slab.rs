@@ -0,0 +1,500 @@
+use core::alloc::Layout;
+use core::{cmp, mem, ptr};
+use spin::Mutex;
+use interrupts;
+use memory::{self, PAGE_SIZE};
+use memory::paging::{Page, EntryFlags};
+
+/// The start address of the slab window.
+///
+/// Located right after the kernel heap.
+pub const SLAB_START: usize = memory::KERNEL_WINDOWS + 0x8000_0000;
+
+/// The size of the virtual range reserved for slabs.
+pub const SLAB_MAX_SIZE: usize = 1024 * 1024 * 1024;
+
+/// The size of the descriptor array at the start of the slab window.
+///
+/// Its pages are mapped as the slab pages they describe are.
+const SLAB_META_SIZE: usize = SLAB_MAX_SIZE / PAGE_SIZE * mem::size_of::<SlabMeta>();
+
+/// The start address of the slab pages.
+const SLAB_PAGES_START: usize = SLAB_START + SLAB_META_SIZE;
+
+/// The smallest object size.
+const MIN_OBJECT_SIZE: usize = 8;
+
+/// The largest object size served by the size classes.
+pub const MAX_OBJECT_SIZE: usize = 2048;
+
+/// The number of size classes.
+pub const SIZE_CLASS_COUNT: usize = 9;
+
+/// The slab pages.
+static SLAB_POOL: Mutex<SlabPool> = Mutex::new(SlabPool {
+    next: SLAB_PAGES_START,
+    free: ptr::null_mut(),
+});
+
+/// The general purpose caches, one per power of two.
+static SIZE_CLASSES: Mutex<[ObjectCache; SIZE_CLASS_COUNT]> = Mutex::new([
+    ObjectCache::new("size-8", 8, 8, None),
+    ObjectCache::new("size-16", 16, 16, None),
+    ObjectCache::new("size-32", 32, 32, None),
+    ObjectCache::new("size-64", 64, 64, None),
+    ObjectCache::new("size-128", 128, 128, None),
+    ObjectCache::new("size-256", 256, 256, None),
+    ObjectCache::new("size-512", 512, 512, None),
+    ObjectCache::new("size-1024", 1024, 1024, None),
+    ObjectCache::new("size-2048", 2048, 2048, None),
+]);
+
+/// Gets the size class serving the layout.
+///
+/// Returns `None` if the layout is too large for the size classes.
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(cmp::max(layout.size(), layout.align()), MIN_OBJECT_SIZE);
+    let size = size.next_power_of_two();
+    if size > MAX_OBJECT_SIZE {
+        None
+    } else {
+        Some((size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize)
+    }
+}
+
+/// Allocates an object from the specified size class.
+///
+/// Returns a null pointer if the slab window or
+/// physical memory is exhausted.
+pub fn allocate(class: usize) -> *mut u8 {
+    interrupts::without_interrupts(|| {
+        SIZE_CLASSES.lock()[class].alloc().unwrap_or(ptr::null_mut())
+    })
+}
+
+/// Returns an object to the specified size class.
+///
+/// # Safety
+///
+/// The object must have been allocated from the same size class.
+pub unsafe fn deallocate(class: usize, object: *mut u8) {
+    interrupts::without_interrupts(|| SIZE_CLASSES.lock()[class].free(object))
+}
+
+/// Gets the statistics of every size class.
+#[allow(dead_code)]
+pub fn size_class_stats() -> [CacheStats; SIZE_CLASS_COUNT] {
+    interrupts::without_interrupts(|| {
+        let caches = SIZE_CLASSES.lock();
+        let mut stats = [CacheStats::new(); SIZE_CLASS_COUNT];
+        for (stat, cache) in stats.iter_mut().zip(caches.iter()) {
+            *stat = cache.stats();
+        }
+        stats
+    })
+}
+
+/// Gets the descriptor of the slab page at the specified address.
+fn slab_meta(page: usize) -> *mut SlabMeta {
+    let index = (page - SLAB_PAGES_START) / PAGE_SIZE;
+    (SLAB_START + index * mem::size_of::<SlabMeta>()) as *mut SlabMeta
+}
+
+/// Gets the address of the slab page with the specified descriptor.
+fn slab_page(slab: *mut SlabMeta) -> usize {
+    let index = (slab as usize - SLAB_START) / mem::size_of::<SlabMeta>();
+    SLAB_PAGES_START + index * PAGE_SIZE
+}
+
+/// Maps a slab page in the slab window and returns its descriptor.
+///
+/// Reuses a page given back by `free_slab_page` if possible.
+/// Returns `None` if the window or physical memory is exhausted.
+fn alloc_slab_page() -> Option<*mut SlabMeta> {
+    interrupts::without_interrupts(|| {
+        let mut pool = SLAB_POOL.lock();
+        let addr = if !pool.free.is_null() {
+            slab_page(pool.free)
+        } else if pool.next < SLAB_START + SLAB_MAX_SIZE {
+            pool.next
+        } else {
+            return None;
+        };
+        let slab = slab_meta(addr);
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let mapped = memory::with_controller_for_alloc(|mc| {
+            // The descriptor pages stay mapped for good
+            let meta_page = Page::get_page_at_address(slab as usize);
+            if mc.active_table.translate_page(meta_page).is_none() &&
+               mc.active_table.map(meta_page, flags, &mut mc.frame_allocator).is_err() {
+                return false;
+            }
+            let page = Page::get_page_at_address(addr);
+            mc.active_table.map(page, flags, &mut mc.frame_allocator).is_ok()
+        });
+        if !mapped {
+            return None;
+        }
+
+        // Only take the page once it is mapped
+        if pool.free == slab {
+            pool.free = unsafe { (*slab).next };
+        } else {
+            pool.next += PAGE_SIZE;
+        }
+        Some(slab)
+    })
+}
+
+/// Unmaps a slab page and keeps it for reuse by `alloc_slab_page`.
+fn free_slab_page(slab: *mut SlabMeta) {
+    interrupts::without_interrupts(|| {
+        let mut pool = SLAB_POOL.lock();
+        memory::with_controller_for_alloc(|mc| {
+            let page = Page::get_page_at_address(slab_page(slab));
+            mc.active_table.unmap(page, &mut mc.frame_allocator);
+        });
+        unsafe {
+            (*slab).next = pool.free;
+        }
+        pool.free = slab;
+    })
+}
+
+/// The `SlabPool` type.
+///
+/// Hands out the pages of the slab window.
+struct SlabPool {
+    /// The address of the next slab page never used.
+    next: usize,
+
+    /// The unmapped slab pages given back, linked through their descriptors.
+    free: *mut SlabMeta,
+}
+
+/// The `Send` implementation for `SlabPool`.
+///
+/// The list only ever points into the slab window.
+unsafe impl Send for SlabPool {}
+
+/// The `SlabMeta` type.
+///
+/// Describes a slab page. Kept outside the page,
+/// so the objects can fill it entirely.
+struct SlabMeta {
+    /// The next slab in the list holding the slab.
+    next: *mut SlabMeta,
+
+    /// The previous slab in the list holding the slab.
+    prev: *mut SlabMeta,
+
+    /// The free objects.
+    free_list: *mut u8,
+
+    /// The number of objects handed out.
+    in_use: usize,
+}
+
+/// The `CacheStats` type.
+#[derive(Debug, Copy, Clone)]
+pub struct CacheStats {
+    /// The number of objects handed out.
+    pub objects_in_use: usize,
+
+    /// The number of free objects.
+    pub objects_free: usize,
+
+    /// The number of slab pages.
+    pub slabs: usize,
+
+    /// The total number of allocations.
+    pub allocations: usize,
+
+    /// The total number of deallocations.
+    pub deallocations: usize,
+}
+
+/// The `CacheStats` implementation.
+impl CacheStats {
+    /// Constructs a new empty `CacheStats`.
+    pub const fn new() -> CacheStats {
+        CacheStats {
+            objects_in_use: 0,
+            objects_free: 0,
+            slabs: 0,
+            allocations: 0,
+            deallocations: 0,
+        }
+    }
+}
+
+/// The `Default` implementation for `CacheStats`.
+impl Default for CacheStats {
+    fn default() -> CacheStats {
+        CacheStats::new()
+    }
+}
+
+/// The `ObjectCache` type.
+///
+/// Represents a cache of equally sized objects, carved
+/// out of whole slab pages backed by single frames.
+pub struct ObjectCache {
+    /// The name.
+    name: &'static str,
+
+    /// The size of an object.
+    object_size: usize,
+
+    /// The alignment of an object.
+    align: usize,
+
+    /// The constructor, run once on every object when its slab is filled.
+    ///
+    /// Objects have to be freed in their constructed state.
+    constructor: Option<fn(*mut u8)>,
+
+    /// The slabs with free objects.
+    partial: *mut SlabMeta,
+
+    /// The number of slabs without objects in use.
+    empty_slabs: usize,
+
+    /// The statistics.
+    stats: CacheStats,
+}
+
+/// The `Send` implementation for `ObjectCache`.
+///
+/// The slab list only ever points into the slab window.
+unsafe impl Send for ObjectCache {}
+
+/// The `ObjectCache` implementation.
+impl ObjectCache {
+    /// Constructs a new empty `ObjectCache`.
+    ///
+    /// The alignment must be a power of two
+    /// no larger than `PAGE_SIZE`.
+    pub const fn new(name: &'static str,
+                     object_size: usize,
+                     align: usize,
+                     constructor: Option<fn(*mut u8)>)
+                     -> ObjectCache {
+        ObjectCache {
+            name,
+            object_size,
+            align,
+            constructor,
+            partial: ptr::null_mut(),
+            empty_slabs: 0,
+            stats: CacheStats::new(),
+        }
+    }
+
+    /// Gets the name.
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets the statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Gets the offset of the free list link inside a free object.
+    ///
+    /// The link is placed after the object if a constructor is set,
+    /// so free objects keep their constructed state.
+    fn link_offset(&self) -> usize {
+        match self.constructor {
+            Some(_) => (self.object_size + MIN_OBJECT_SIZE - 1) & !(MIN_OBJECT_SIZE - 1),
+            None => 0,
+        }
+    }
+
+    /// Gets the distance between two objects in a slab.
+    fn stride(&self) -> usize {
+        let align = cmp::max(self.align, MIN_OBJECT_SIZE);
+        let size = cmp::max(self.object_size, self.link_offset() + MIN_OBJECT_SIZE);
+        (size + align - 1) & !(align - 1)
+    }
+
+    /// Gets the link of the specified free object.
+    ///
+    /// # Safety
+    ///
+    /// The object must belong to this cache.
+    unsafe fn link(&self, object: *mut u8) -> *mut *mut u8 {
+        object.add(self.link_offset()) as *mut *mut u8
+    }
+
+    /// Allocates an object.
+    ///
+    /// Returns `None` if no slab page could be added.
+    pub fn alloc(&mut self) -> Option<*mut u8> {
+        if self.partial.is_null() && !self.grow() {
+            return None;
+        }
+        let slab = self.partial;
+        let object = unsafe {
+            let object = (*slab).free_list;
+            (*slab).free_list = *self.link(object);
+            if (*slab).in_use == 0 {
+                self.empty_slabs -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free_list.is_null() {
+                self.unlink(slab);
+            }
+            object
+        };
+        self.stats.objects_free -= 1;
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+        Some(object)
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// A slab left without objects in use is given back
+    /// if the cache already holds another empty slab, so
+    /// alternating allocations don't map and unmap a page each.
+    ///
+    /// # Safety
+    ///
+    /// The object must have been allocated from this cache.
+    pub unsafe fn free(&mut self, object: *mut u8) {
+        let slab = slab_meta(object as usize & !(PAGE_SIZE - 1));
+        let was_full = (*slab).free_list.is_null();
+        ptr::write(self.link(object), (*slab).free_list);
+        (*slab).free_list = object;
+        (*slab).in_use -= 1;
+        self.stats.objects_free += 1;
+        self.stats.objects_in_use -= 1;
+        self.stats.deallocations += 1;
+
+        if (*slab).in_use == 0 && self.empty_slabs > 0 {
+            if !was_full {
+                self.unlink(slab);
+            }
+            free_slab_page(slab);
+            self.stats.slabs -= 1;
+            self.stats.objects_free -= PAGE_SIZE / self.stride();
+            return;
+        }
+        if (*slab).in_use == 0 {
+            self.empty_slabs += 1;
+        }
+        if was_full {
+            self.push(slab);
+        }
+    }
+
+    /// Adds a slab page to the cache.
+    ///
+    /// Runs the constructor on every object of the page.
+    fn grow(&mut self) -> bool {
+        let slab = match alloc_slab_page() {
+            Some(slab) => slab,
+            None => return false,
+        };
+        let page = slab_page(slab);
+
+        // Link the objects in reverse, so they are handed out in order
+        let stride = self.stride();
+        let count = PAGE_SIZE / stride;
+        let mut free_list = ptr::null_mut();
+        for index in (0..count).rev() {
+            let object = (page + index * stride) as *mut u8;
+            if let Some(constructor) = self.constructor {
+                constructor(object);
+            }
+            unsafe {
+                ptr::write(self.link(object), free_list);
+            }
+            free_list = object;
+        }
+        unsafe {
+            ptr::write(slab,
+                       SlabMeta {
+                           next: ptr::null_mut(),
+                           prev: ptr::null_mut(),
+                           free_list,
+                           in_use: 0,
+                       });
+        }
+        self.push(slab);
+        self.empty_slabs += 1;
+        self.stats.slabs += 1;
+        self.stats.objects_free += count;
+        true
+    }
+
+    /// Adds a slab to the slabs with free objects.
+    fn push(&mut self, slab: *mut SlabMeta) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    /// Removes a slab from the slabs with free objects.
+    fn unlink(&mut self, slab: *mut SlabMeta) {
+        unsafe {
+            let (prev, next) = ((*slab).prev, (*slab).next);
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gets the size class serving the specified size and alignment.
+    fn class(size: usize, align: usize) -> Option<usize> {
+        size_class(&Layout::from_size_align(size, align).unwrap())
+    }
+
+    #[test]
+    fn size_class_rounds_small_sizes_up_to_the_smallest_class() {
+        assert_eq!(class(0, 1), Some(0));
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(MIN_OBJECT_SIZE, 8), Some(0));
+    }
+
+    #[test]
+    fn size_class_rounds_up_to_the_next_power_of_two() {
+        for index in 0..SIZE_CLASS_COUNT {
+            let size = MIN_OBJECT_SIZE << index;
+            assert_eq!(class(size, 1), Some(index));
+            assert_eq!(class(size / 2 + 1, 1), Some(index));
+        }
+        assert_eq!(class(MAX_OBJECT_SIZE, 1), Some(SIZE_CLASS_COUNT - 1));
+    }
+
+    #[test]
+    fn size_class_honours_the_alignment() {
+        assert_eq!(class(8, 64), Some(3));
+        assert_eq!(class(100, 256), Some(5));
+        assert_eq!(class(8, MAX_OBJECT_SIZE), Some(SIZE_CLASS_COUNT - 1));
+    }
+
+    #[test]
+    fn size_class_rejects_large_layouts() {
+        assert_eq!(class(MAX_OBJECT_SIZE + 1, 1), None);
+        assert_eq!(class(8, PAGE_SIZE), None);
+    }
+}
@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use interrupts;
+use memory::{self, PAGE_SIZE};
+use memory::paging::{Page, EntryFlags};
+
+/// The start address of the kernel stack window.
+///
+/// Located 4 GiB into the kernel windows.
+pub const STACK_AREA_START: usize = memory::KERNEL_WINDOWS + 0x1_0000_0000;
+
+/// The size of the virtual range reserved for kernel stacks.
+pub const STACK_AREA_SIZE: usize = 1024 * 1024 * 1024;
+
+/// The address of the next unused stack slot.
+static NEXT_STACK: AtomicUsize = AtomicUsize::new(STACK_AREA_START);
+
+/// The `Stack` type.
+///
+/// Represents a kernel stack with an unmapped guard page below it.
+#[derive(Debug)]
+pub struct Stack {
+    /// The top address, exclusive.
+    top: usize,
+
+    /// The bottom address.
+    bottom: usize,
+}
+
+/// The `Stack` implementation.
+#[allow(dead_code)]
+impl Stack {
+    /// Gets the top address.
+    ///
+    /// The stack grows downwards from here.
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Gets the bottom address.
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    /// Gets the size in bytes.
+    pub fn size(&self) -> usize {
+        self.top - self.bottom
+    }
+}
+
+/// Allocates a kernel stack of the specified number of pages.
+///
+/// Returns `None` if the stack window or physical memory is exhausted.
+#[allow(dead_code)]
+pub fn alloc_stack(pages: usize) -> Option<Stack> {
+    assert!(pages > 0);
+
+    // Reserve the stack and its guard page
+    let guard = NEXT_STACK.fetch_add((pages + 1) * PAGE_SIZE, Ordering::SeqCst);
+    let bottom = guard + PAGE_SIZE;
+    let top = bottom + pages * PAGE_SIZE;
+    if top > STACK_AREA_START + STACK_AREA_SIZE {
+        return None;
+    }
+
+    interrupts::without_interrupts(|| {
+        memory::with_controller(|mc| {
+            let mut mapped = 0;
+            for page in Page::range_containing(bottom, top) {
+                let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+                if mc.active_table.map(page, flags, &mut mc.frame_allocator).is_err() {
+                    break;
+                }
+                mapped += 1;
+            }
+
+            // Roll back if the mapping failed
+            if mapped < pages {
+                let mapped_pages = Page::range_containing(bottom, bottom + mapped * PAGE_SIZE);
+                mc.active_table.unmap_range(mapped_pages, &mut mc.frame_allocator);
+                return None;
+            }
+            Some(Stack { top, bottom })
+        })
+    })
+}
+
+/// Frees a kernel stack.
+///
+/// The frames go back to the frame allocator,
+/// while the virtual range is not reused.
+#[allow(dead_code)]
+pub fn dealloc_stack(stack: Stack) {
+    interrupts::without_interrupts(|| {
+        memory::with_controller(|mc| {
+            let pages = Page::range_containing(stack.bottom, stack.top);
+            mc.active_table.unmap_range(pages, &mut mc.frame_allocator);
+        })
+    });
+}
@@ -0,0 +1,170 @@
+use core::mem;
+
+/// The multiboot2 end tag type.
+const TAG_END: u32 = 0;
+
+/// The multiboot2 ELF sections tag type.
+const TAG_ELF_SECTIONS: u32 = 9;
+
+/// The multiboot2 tag type holding an ACPI 1.0 RSDP.
+const TAG_ACPI_OLD_RSDP: u32 = 14;
+
+/// The multiboot2 tag type holding an ACPI 2.0 RSDP.
+const TAG_ACPI_NEW_RSDP: u32 = 15;
+
+/// The ELF type of an unused section header.
+const ELF_SECTION_UNUSED: u32 = 0;
+
+/// Loads the multiboot2 boot information at the specified address.
+///
+/// # Safety
+///
+/// The address must point to the boot information, which has to
+/// stay mapped and untouched for as long as the returned value is used.
+pub unsafe fn load(addr: usize) -> BootInformation {
+    BootInformation {
+        addr,
+        total_size: *(addr as *const u32),
+    }
+}
+
+/// The `BootInformation` type.
+pub struct BootInformation {
+    /// The address of the boot information.
+    addr: usize,
+
+    /// The size of the boot information in bytes.
+    pub total_size: u32,
+}
+
+/// The `BootInformation` implementation.
+impl BootInformation {
+    /// Gets the ELF sections tag.
+    pub fn elf_sections_tag(&self) -> Option<&'static ElfSectionsTag> {
+        self.find_tag(TAG_ELF_SECTIONS)
+            .map(|tag| unsafe { &*(tag as *const ElfSectionsTag) })
+    }
+
+    /// Gets the address of the ACPI RSDP copied into the boot information.
+    ///
+    /// Prefers the ACPI 2.0 RSDP over the ACPI 1.0 one.
+    pub fn rsdp_addr(&self) -> Option<usize> {
+        self.find_tag(TAG_ACPI_NEW_RSDP)
+            .or_else(|| self.find_tag(TAG_ACPI_OLD_RSDP))
+            .map(|tag| tag + 8)
+    }
+
+    /// Finds the first tag of the specified type.
+    fn find_tag(&self, typ: u32) -> Option<usize> {
+        let mut tag = self.addr + 8;
+        loop {
+            let (tag_type, size) = unsafe {
+                (*(tag as *const u32), *((tag + 4) as *const u32) as usize)
+            };
+            match tag_type {
+                TAG_END => return None,
+                _ if tag_type == typ => return Some(tag),
+                _ => tag += (size + 7) & !7,
+            }
+        }
+    }
+}
+
+/// The `ElfSectionsTag` type.
+///
+/// Holds the section headers of the kernel image.
+#[repr(C)]
+pub struct ElfSectionsTag {
+    /// The tag type.
+    typ: u32,
+
+    /// The tag size in bytes.
+    size: u32,
+
+    /// The number of section headers.
+    number_of_sections: u32,
+
+    /// The size of a section header.
+    entry_size: u32,
+
+    /// The index of the section name string table.
+    shndx: u32,
+}
+
+/// The `ElfSectionsTag` implementation.
+impl ElfSectionsTag {
+    /// Gets an iterator over the used sections.
+    pub fn sections(&self) -> ElfSectionIter {
+        ElfSectionIter {
+            current: self as *const _ as usize + mem::size_of::<ElfSectionsTag>(),
+            remaining: self.number_of_sections,
+            entry_size: self.entry_size as usize,
+        }
+    }
+}
+
+/// The `ElfSectionIter` type.
+pub struct ElfSectionIter {
+    /// The address of the next section header.
+    current: usize,
+
+    /// The number of section headers left.
+    remaining: u32,
+
+    /// The size of a section header.
+    entry_size: usize,
+}
+
+/// The `Iterator` implementation for `ElfSectionIter`.
+impl Iterator for ElfSectionIter {
+    type Item = &'static ElfSection;
+
+    /// Gets the next used section.
+    fn next(&mut self) -> Option<&'static ElfSection> {
+        while self.remaining > 0 {
+            let section = unsafe { &*(self.current as *const ElfSection) };
+            self.current += self.entry_size;
+            self.remaining -= 1;
+            if section.typ != ELF_SECTION_UNUSED {
+                return Some(section);
+            }
+        }
+        None
+    }
+}
+
+/// The `ElfSection` type.
+///
+/// Represents a 64-bit ELF section header.
+#[repr(C)]
+pub struct ElfSection {
+    /// The offset of the name in the string table.
+    name: u32,
+
+    /// The section type.
+    typ: u32,
+
+    /// The section flags.
+    pub flags: u64,
+
+    /// The virtual address.
+    pub addr: u64,
+
+    /// The offset in the file.
+    offset: u64,
+
+    /// The size in bytes.
+    pub size: u64,
+
+    /// The index of a linked section.
+    link: u32,
+
+    /// Extra type-specific information.
+    info: u32,
+
+    /// The required alignment.
+    addralign: u64,
+
+    /// The size of fixed-size entries.
+    entry_size: u64,
+}
@@ -0,0 +1,155 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The capacity of a ring buffer.
+///
+/// Must be a power of two.
+pub const RING_BUFFER_SIZE: usize = 1024;
+
+/// The `RingBuffer` type.
+///
+/// Represents a lock-free single-producer, single-consumer
+/// byte queue, used to pass bytes between interrupt handlers
+/// and the rest of the kernel.
+pub struct RingBuffer {
+    /// The bytes.
+    buffer: UnsafeCell<[u8; RING_BUFFER_SIZE]>,
+
+    /// The number of bytes ever pushed.
+    head: AtomicUsize,
+
+    /// The number of bytes ever popped.
+    tail: AtomicUsize,
+}
+
+/// The `Sync` implementation for `RingBuffer`.
+///
+/// The producer only writes the slot at `head` before publishing it,
+/// and the consumer only reads the slot at `tail` before releasing it.
+unsafe impl Sync for RingBuffer {}
+
+/// The `RingBuffer` implementation.
+impl RingBuffer {
+    /// Constructs a new empty `RingBuffer`.
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            buffer: UnsafeCell::new([0; RING_BUFFER_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes a byte.
+    ///
+    /// Returns `false` if the buffer is full.
+    /// Must only be called by the producer.
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == RING_BUFFER_SIZE {
+            return false;
+        }
+        unsafe {
+            (*self.buffer.get())[head % RING_BUFFER_SIZE] = byte;
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pops a byte.
+    ///
+    /// Returns `None` if the buffer is empty.
+    /// Must only be called by the consumer.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = unsafe { (*self.buffer.get())[tail % RING_BUFFER_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Gets the number of buffered bytes.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+
+    /// Tests if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The `Default` implementation for `RingBuffer`.
+impl Default for RingBuffer {
+    fn default() -> RingBuffer {
+        RingBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop_in_order() {
+        let buffer = RingBuffer::new();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop(), None);
+        for byte in 0..10 {
+            assert!(buffer.push(byte));
+        }
+        assert_eq!(buffer.len(), 10);
+        for byte in 0..10 {
+            assert_eq!(buffer.pop(), Some(byte));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn push_fails_when_full() {
+        let buffer = RingBuffer::new();
+        for index in 0..RING_BUFFER_SIZE {
+            assert!(buffer.push(index as u8));
+        }
+        assert_eq!(buffer.len(), RING_BUFFER_SIZE);
+        assert!(!buffer.push(0xff));
+        assert_eq!(buffer.pop(), Some(0));
+        assert!(buffer.push(0xff));
+        assert!(!buffer.push(0xff));
+    }
+
+    #[test]
+    fn slots_wrap_around() {
+        let buffer = RingBuffer::new();
+        for round in 0..3 * RING_BUFFER_SIZE {
+            assert!(buffer.push(round as u8));
+            assert!(buffer.push((round + 1) as u8));
+            assert_eq!(buffer.pop(), Some(round as u8));
+            assert_eq!(buffer.pop(), Some((round + 1) as u8));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn counters_wrap_around() {
+        let buffer = RingBuffer {
+            buffer: UnsafeCell::new([0; RING_BUFFER_SIZE]),
+            head: AtomicUsize::new(usize::MAX - 1),
+            tail: AtomicUsize::new(usize::MAX - 1),
+        };
+        for byte in 0..4 {
+            assert!(buffer.push(byte));
+        }
+        assert_eq!(buffer.len(), 4);
+        for byte in 0..4 {
+            assert_eq!(buffer.pop(), Some(byte));
+        }
+        assert!(buffer.is_empty());
+    }
+}
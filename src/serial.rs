@@ -1,37 +1,368 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
-use cpuio::{inb, outb};
+use cpu::{inb, outb};
+use interrupts::{self, irq};
+use ring_buffer::{RingBuffer, RING_BUFFER_SIZE};
 
 macro_rules! serial_data { ($port:expr) => ($port + 0) }
+macro_rules! serial_divisor_low { ($port:expr) => ($port + 0) }
 macro_rules! serial_ier { ($port:expr) => ($port + 1) }
+macro_rules! serial_divisor_high { ($port:expr) => ($port + 1) }
 macro_rules! serial_fifo { ($port:expr) => ($port + 2) }
+macro_rules! serial_iir { ($port:expr) => ($port + 2) }
 macro_rules! serial_line { ($port:expr) => ($port + 3) }
 macro_rules! serial_modem { ($port:expr) => ($port + 4) }
 macro_rules! serial_line_status { ($port:expr) => ($port + 5) }
+macro_rules! serial_modem_status { ($port:expr) => ($port + 6) }
+macro_rules! serial_scratch { ($port:expr) => ($port + 7) }
+
+/// The port of COM1.
+const COM1_PORT: u16 = 0x3F8;
+
+/// The port of COM2.
+const COM2_PORT: u16 = 0x2F8;
+
+/// The port of COM3.
+const COM3_PORT: u16 = 0x3E8;
+
+/// The port of COM4.
+const COM4_PORT: u16 = 0x2E8;
+
+/// The received data available interrupt.
+const IER_RX_AVAILABLE: u8 = 0x01;
+
+/// The transmitter holding register empty interrupt.
+const IER_TX_EMPTY: u8 = 0x02;
+
+/// The modem status change interrupt.
+const IER_MODEM_STATUS: u8 = 0x08;
+
+/// The divisor latch access bit of the line control register.
+const LINE_DLAB: u8 = 0x80;
+
+/// The FIFO control bits that enable and clear both FIFOs.
+const FIFO_ENABLE_AND_CLEAR: u8 = 0x07;
+
+/// The modem control bits for normal operation (DTR, RTS, OUT1 and OUT2).
+const MODEM_NORMAL: u8 = 0x0F;
+
+/// The modem control bits for the loopback self-test (RTS, OUT1, OUT2 and LOOP).
+const MODEM_LOOPBACK: u8 = 0x1E;
+
+/// The modem control bit driving RTS.
+const MODEM_RTS: u8 = 0x02;
+
+/// The modem status bit set while CTS is asserted.
+const MODEM_STATUS_CTS: u8 = 0x10;
+
+/// The UART input clock divided by 16, which is the highest baud rate.
+const MAX_BAUD_RATE: u32 = 115200;
+
+/// The size of the transmit FIFO.
+const TX_FIFO_SIZE: usize = 16;
+
+/// The number of line status polls before the loopback self-test fails.
+const LOOPBACK_TIMEOUT: usize = 100_000;
+
+/// The number of modem status polls before a polled write
+/// stops waiting for CTS and sends the byte anyway.
+const CTS_TIMEOUT: usize = 10_000;
+
+/// The number of received bytes at which RTS is deasserted.
+const RX_HIGH_WATER: usize = RING_BUFFER_SIZE * 3 / 4;
+
+/// The number of received bytes at which RTS is asserted again.
+const RX_LOW_WATER: usize = RING_BUFFER_SIZE / 4;
+
+/// The `DataBits` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum DataBits {
+    Five = 0b00,
+    Six = 0b01,
+    Seven = 0b10,
+    Eight = 0b11,
+}
+
+/// The `Parity` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Parity {
+    None = 0b000 << 3,
+    Odd = 0b001 << 3,
+    Even = 0b011 << 3,
+    Mark = 0b101 << 3,
+    Space = 0b111 << 3,
+}
+
+/// The `StopBits` type.
+///
+/// Two stop bits mean 1.5 stop bits with five data bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum StopBits {
+    One = 0 << 2,
+    Two = 1 << 2,
+}
+
+/// The `FifoTrigger` type.
+///
+/// Represents the number of received bytes
+/// that raise the received data interrupt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum FifoTrigger {
+    Bytes1 = 0b00 << 6,
+    Bytes4 = 0b01 << 6,
+    Bytes8 = 0b10 << 6,
+    Bytes14 = 0b11 << 6,
+}
+
+/// The `SerialConfig` type.
+#[derive(Debug, Copy, Clone)]
+pub struct SerialConfig {
+    /// The baud rate in bits per second.
+    pub baud_rate: u32,
+
+    /// The number of data bits.
+    pub data_bits: DataBits,
+
+    /// The parity.
+    pub parity: Parity,
+
+    /// The number of stop bits.
+    pub stop_bits: StopBits,
+
+    /// The receive FIFO trigger level.
+    pub fifo_trigger: FifoTrigger,
+
+    /// Whether RTS/CTS hardware flow control is used.
+    pub flow_control: bool,
+}
+
+/// The `SerialConfig` implementation.
+impl SerialConfig {
+    /// Constructs a new `SerialConfig` with 38400 baud,
+    /// 8N1 and no flow control.
+    pub const fn new() -> SerialConfig {
+        SerialConfig {
+            baud_rate: 38400,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo_trigger: FifoTrigger::Bytes14,
+            flow_control: false,
+        }
+    }
+}
+
+/// The `Default` implementation for `SerialConfig`.
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig::new()
+    }
+}
+
+/// The `SerialError` type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SerialError {
+    /// No UART responded at the port.
+    NotPresent,
+
+    /// The UART failed the loopback self-test.
+    SelfTestFailed,
+
+    /// The baud rate can't be derived from the UART clock.
+    InvalidBaudRate,
+}
+
+/// The buffers of COM1.
+static COM1_BUFFERS: SerialBuffers = SerialBuffers::new();
+
+/// The buffers of COM2.
+static COM2_BUFFERS: SerialBuffers = SerialBuffers::new();
+
+/// The buffers of COM3.
+static COM3_BUFFERS: SerialBuffers = SerialBuffers::new();
+
+/// The buffers of COM4.
+static COM4_BUFFERS: SerialBuffers = SerialBuffers::new();
 
 /// A serial writer to COM1.
 pub static COM1: Mutex<SerialWriter> = Mutex::new(SerialWriter {
-    port: 0x3F8,
+    port: COM1_PORT,
     irq: 4,
+    present: false,
+    buffers: &COM1_BUFFERS,
 });
 
 /// A serial writer to COM2.
+#[allow(dead_code)]
 pub static COM2: Mutex<SerialWriter> = Mutex::new(SerialWriter {
-    port: 0x2F8,
+    port: COM2_PORT,
     irq: 3,
+    present: false,
+    buffers: &COM2_BUFFERS,
 });
 
 /// A serial writer to COM3.
+#[allow(dead_code)]
 pub static COM3: Mutex<SerialWriter> = Mutex::new(SerialWriter {
-    port: 0x3E8,
+    port: COM3_PORT,
     irq: 4,
+    present: false,
+    buffers: &COM3_BUFFERS,
 });
 
 /// A serial writer to COM4.
+#[allow(dead_code)]
 pub static COM4: Mutex<SerialWriter> = Mutex::new(SerialWriter {
-    port: 0x2E8,
+    port: COM4_PORT,
     irq: 3,
+    present: false,
+    buffers: &COM4_BUFFERS,
 });
 
+/// The `SerialBuffers` type.
+///
+/// Holds the state shared between a `SerialWriter`
+/// and its interrupt handler, outside of the writer's lock.
+struct SerialBuffers {
+    /// The received bytes.
+    rx: RingBuffer,
+
+    /// The bytes waiting to be transmitted.
+    tx: RingBuffer,
+
+    /// Whether the port is interrupt-driven.
+    interrupt_driven: AtomicBool,
+
+    /// Whether the port uses RTS/CTS flow control.
+    flow_control: AtomicBool,
+
+    /// Whether RTS is deasserted because the receive buffer is nearly full.
+    rts_held: AtomicBool,
+}
+
+/// The `SerialBuffers` implementation.
+impl SerialBuffers {
+    /// Constructs a new `SerialBuffers`.
+    const fn new() -> SerialBuffers {
+        SerialBuffers {
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            interrupt_driven: AtomicBool::new(false),
+            flow_control: AtomicBool::new(false),
+            rts_held: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Handles IRQ 4, shared by COM1 and COM3.
+fn handle_irq4() {
+    service(COM1_PORT, &COM1_BUFFERS);
+    service(COM3_PORT, &COM3_BUFFERS);
+}
+
+/// Handles IRQ 3, shared by COM2 and COM4.
+fn handle_irq3() {
+    service(COM2_PORT, &COM2_BUFFERS);
+    service(COM4_PORT, &COM4_BUFFERS);
+}
+
+/// Tests if the other side is ready to receive.
+fn clear_to_send(port: u16, buffers: &SerialBuffers) -> bool {
+    !buffers.flow_control.load(Ordering::Acquire) ||
+    unsafe { inb(serial_modem_status!(port)) } & MODEM_STATUS_CTS != 0
+}
+
+/// Drives RTS from the fill level of the receive buffer.
+///
+/// Deasserts RTS once the buffer is nearly full, so the other
+/// side pauses before bytes are dropped, and asserts it again
+/// once the buffer has drained. Must run with interrupts disabled.
+fn update_rts(port: u16, buffers: &SerialBuffers) {
+    if !buffers.flow_control.load(Ordering::Acquire) {
+        return;
+    }
+    let len = buffers.rx.len();
+    let held = buffers.rts_held.load(Ordering::Acquire);
+    if !held && len >= RX_HIGH_WATER {
+        unsafe {
+            let modem = inb(serial_modem!(port));
+            outb(modem & !MODEM_RTS, serial_modem!(port));
+        }
+        buffers.rts_held.store(true, Ordering::Release);
+    } else if held && len <= RX_LOW_WATER {
+        unsafe {
+            let modem = inb(serial_modem!(port));
+            outb(modem | MODEM_RTS, serial_modem!(port));
+        }
+        buffers.rts_held.store(false, Ordering::Release);
+    }
+}
+
+/// Services every pending interrupt of a port.
+fn service(port: u16, buffers: &SerialBuffers) {
+    if !buffers.interrupt_driven.load(Ordering::Acquire) {
+        return;
+    }
+    loop {
+        // Bit 0 of the interrupt identification is clear while
+        // an interrupt is pending
+        let iir = unsafe { inb(serial_iir!(port)) };
+        if iir & 1 != 0 {
+            break;
+        }
+        match (iir >> 1) & 0b111 {
+            // Received data available or character timeout
+            0b010 | 0b110 => {
+                while unsafe { inb(serial_line_status!(port)) } & 1 != 0 {
+                    let byte = unsafe { inb(serial_data!(port)) };
+                    // Drop the byte if nobody is reading
+                    buffers.rx.push(byte);
+                }
+                update_rts(port, buffers);
+            }
+            // Transmitter holding register empty
+            0b001 => {
+                for _ in 0..TX_FIFO_SIZE {
+                    // Stop until CTS is asserted again
+                    if !clear_to_send(port, buffers) {
+                        unsafe {
+                            let ier = inb(serial_ier!(port));
+                            outb(ier & !IER_TX_EMPTY, serial_ier!(port));
+                        }
+                        break;
+                    }
+                    match buffers.tx.pop() {
+                        Some(byte) => unsafe { outb(byte, serial_data!(port)) },
+                        None => {
+                            unsafe {
+                                let ier = inb(serial_ier!(port));
+                                outb(ier & !IER_TX_EMPTY, serial_ier!(port));
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+            // Modem status changed
+            0b000 => unsafe {
+                let status = inb(serial_modem_status!(port));
+                if status & MODEM_STATUS_CTS != 0 && !buffers.tx.is_empty() {
+                    let ier = inb(serial_ier!(port));
+                    outb(ier | IER_TX_EMPTY, serial_ier!(port));
+                }
+            },
+            // Line status changed
+            _ => unsafe {
+                inb(serial_line_status!(port));
+            },
+        }
+    }
+}
+
 /// The `SerialWriter` type.
 pub struct SerialWriter {
     /// The port.
@@ -39,6 +370,12 @@ pub struct SerialWriter {
 
     /// The IRQ.
     irq: u8,
+
+    /// Whether a UART was detected at the port.
+    present: bool,
+
+    /// The buffers.
+    buffers: &'static SerialBuffers,
 }
 
 /// The `::core::fmt::Write` implementation for `SerialWriter`.
@@ -56,42 +393,136 @@ impl ::core::fmt::Write for SerialWriter {
 /// The `SerialWriter` implementation.
 impl SerialWriter {
     /// Initializes the serial writer.
-    #[inline(always)]
-    pub fn init(&self) {
+    ///
+    /// Detects the UART through the scratch register and runs
+    /// a loopback self-test before applying the configuration.
+    /// Writes to a port that failed initialization are dropped.
+    pub fn init(&mut self, config: SerialConfig) -> Result<(), SerialError> {
+        self.present = false;
+
+        // Test if the scratch register holds a value
+        for &value in &[0x55, 0xAA] {
+            unsafe {
+                outb(value, serial_scratch!(self.port));
+                if inb(serial_scratch!(self.port)) != value {
+                    return Err(SerialError::NotPresent);
+                }
+            }
+        }
+
+        // Disable interrupts and apply the configuration
         unsafe {
-            outb(0x00, serial_data!(self.port));
-            outb(0x80, serial_line!(self.port));
-            outb(0x03, serial_data!(self.port));
             outb(0x00, serial_ier!(self.port));
-            outb(0x03, serial_line!(self.port));
-            outb(0xc7, serial_fifo!(self.port));
-            outb(0x0b, serial_modem!(self.port));
         }
+        self.set_baud_rate(config.baud_rate)?;
+        self.set_line(config.data_bits, config.parity, config.stop_bits);
+        self.enable_buffer(config.fifo_trigger);
+
+        // Test if a byte sent in loopback mode comes back,
+        // giving it time to be shifted out and in again
+        unsafe {
+            outb(MODEM_LOOPBACK, serial_modem!(self.port));
+            outb(0xAE, serial_data!(self.port));
+        }
+        let received = (0..LOOPBACK_TIMEOUT).any(|_| self.buffer_contains_data());
+        if !received || unsafe { inb(serial_data!(self.port)) } != 0xAE {
+            return Err(SerialError::SelfTestFailed);
+        }
+        unsafe {
+            outb(MODEM_NORMAL, serial_modem!(self.port));
+        }
+
+        self.buffers.flow_control.store(config.flow_control, Ordering::Release);
+        self.buffers.rts_held.store(false, Ordering::Release);
+        self.present = true;
+        Ok(())
+    }
+
+    /// Tests if a UART was detected at the port.
+    #[allow(dead_code)]
+    pub fn is_present(&self) -> bool {
+        self.present
     }
 
-    /// Sets the baud rate.
+    /// Enables the receive and transmit interrupts.
+    ///
+    /// Registers the handler of the IRQ line shared with
+    /// the sibling port, unless that port already did.
+    pub fn enable_interrupts(&self) {
+        if !self.present {
+            return;
+        }
+        let handler: irq::IrqHandler = match self.irq {
+            4 => handle_irq4,
+            _ => handle_irq3,
+        };
+        if !irq::is_registered(self.irq) {
+            irq::register(self.irq, handler);
+        }
+        let mut ier = IER_RX_AVAILABLE;
+        if self.buffers.flow_control.load(Ordering::Acquire) {
+            ier |= IER_MODEM_STATUS;
+        }
+        interrupts::without_interrupts(|| {
+            self.buffers.interrupt_driven.store(true, Ordering::Release);
+            unsafe {
+                outb(ier, serial_ier!(self.port));
+            }
+        });
+    }
+
+    /// Tests if the writer is interrupt-driven right now.
+    ///
+    /// Falls back to polling while interrupts are disabled,
+    /// e.g. in exception handlers.
+    #[inline(always)]
+    fn is_interrupt_driven(&self) -> bool {
+        self.buffers.interrupt_driven.load(Ordering::Acquire) && interrupts::are_enabled()
+    }
+
+    /// Starts transmitting the buffered bytes.
     #[inline(always)]
-    pub fn set_baud_rate(&self, divisor: u32) {
+    fn start_transmit(&self) {
+        interrupts::without_interrupts(|| unsafe {
+            let ier = inb(serial_ier!(self.port));
+            outb(ier | IER_TX_EMPTY, serial_ier!(self.port));
+        });
+    }
+
+    /// Sets the baud rate in bits per second.
+    ///
+    /// The baud rate must evenly divide `MAX_BAUD_RATE`.
+    pub fn set_baud_rate(&self, baud_rate: u32) -> Result<(), SerialError> {
+        if baud_rate == 0 || !MAX_BAUD_RATE.is_multiple_of(baud_rate) {
+            return Err(SerialError::InvalidBaudRate);
+        }
+        let divisor = MAX_BAUD_RATE / baud_rate;
         unsafe {
-            outb(0x80, serial_line!(self.port));
-            outb(((divisor >> 8) & 0x00FF) as u8, serial_data!(self.port));
-            outb((divisor & 0x00FF) as u8, serial_data!(self.port));
+            // The divisor latch shares its ports with the
+            // data and interrupt enable registers
+            let line = inb(serial_line!(self.port));
+            outb(line | LINE_DLAB, serial_line!(self.port));
+            outb((divisor & 0x00FF) as u8, serial_divisor_low!(self.port));
+            outb(((divisor >> 8) & 0x00FF) as u8, serial_divisor_high!(self.port));
+            outb(line & !LINE_DLAB, serial_line!(self.port));
         }
+        Ok(())
     }
 
     /// Sets the line.
     #[inline(always)]
-    pub fn set_line(&self) {
+    pub fn set_line(&self, data_bits: DataBits, parity: Parity, stop_bits: StopBits) {
+        let line = data_bits as u8 | parity as u8 | stop_bits as u8;
         unsafe {
-            outb(0x03, serial_line!(self.port));
+            outb(line, serial_line!(self.port));
         }
     }
 
     /// Enables the buffer.
     #[inline(always)]
-    pub fn enable_buffer(&self) {
+    pub fn enable_buffer(&self, trigger: FifoTrigger) {
         unsafe {
-            outb(0xc7, serial_fifo!(self.port));
+            outb(FIFO_ENABLE_AND_CLEAR | trigger as u8, serial_fifo!(self.port));
         }
     }
 
@@ -107,32 +538,129 @@ impl SerialWriter {
         unsafe { (inb(serial_line_status!(self.port)) & 1) > 0 }
     }
 
-    /// Writes a byte.
+    /// Writes a byte by polling the line status.
+    ///
+    /// Stops waiting for CTS after `CTS_TIMEOUT` polls, so a
+    /// missing peer can't hang the kernel, e.g. while panicking.
     #[inline(always)]
-    pub fn write_byte(&self, byte: u8) {
+    fn poll_write_byte(&self, byte: u8) {
+        for _ in 0..CTS_TIMEOUT {
+            if clear_to_send(self.port, self.buffers) {
+                break;
+            }
+        }
         while !self.buffer_is_empty() {}
         unsafe {
             outb(byte, self.port);
         }
     }
 
+    /// Writes a byte.
+    ///
+    /// Waits for the transmit interrupt if the buffer is full.
+    #[inline(always)]
+    pub fn write_byte(&self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        if !self.is_interrupt_driven() {
+            // The interrupt handler can't run, so drain the
+            // buffered bytes first to keep them in order
+            while let Some(buffered) = self.buffers.tx.pop() {
+                self.poll_write_byte(buffered);
+            }
+            self.poll_write_byte(byte);
+            return;
+        }
+        loop {
+            interrupts::disable();
+            if self.buffers.tx.push(byte) {
+                interrupts::enable();
+                break;
+            }
+            interrupts::enable_and_halt();
+        }
+        self.start_transmit();
+    }
+
+    /// Writes as many bytes as fit into the transmit buffer.
+    ///
+    /// Returns the number of bytes written without blocking.
+    #[allow(dead_code)]
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        if !self.present {
+            return 0;
+        }
+        if !self.is_interrupt_driven() {
+            for &byte in bytes {
+                self.write_byte(byte);
+            }
+            return bytes.len();
+        }
+        let mut count = 0;
+        for &byte in bytes {
+            if !self.buffers.tx.push(byte) {
+                break;
+            }
+            count += 1;
+        }
+        if count > 0 {
+            self.start_transmit();
+        }
+        count
+    }
+
     /// Writes a string.
     #[inline(always)]
+    #[allow(dead_code)]
     pub fn write_str(&self, string: &str) {
         for byte in string.bytes() {
             self.write_byte(byte);
         }
     }
 
+    /// Reads a byte without blocking.
+    #[inline(always)]
+    #[allow(dead_code)]
+    pub fn try_read(&self) -> Option<u8> {
+        if !self.present {
+            None
+        } else if self.buffers.interrupt_driven.load(Ordering::Acquire) {
+            let byte = self.buffers.rx.pop();
+            interrupts::without_interrupts(|| update_rts(self.port, self.buffers));
+            byte
+        } else if self.buffer_contains_data() {
+            Some(unsafe { inb(self.port) })
+        } else {
+            None
+        }
+    }
+
     /// Reads a byte.
+    ///
+    /// Waits for the receive interrupt if no byte is buffered.
     #[inline(always)]
+    #[allow(dead_code)]
     pub fn read_byte(&self) -> u8 {
-        while !self.buffer_contains_data() {}
-        unsafe { inb(self.port) }
+        assert!(self.present, "Reading from a missing serial port");
+        if !self.is_interrupt_driven() {
+            while !self.buffer_contains_data() {}
+            return unsafe { inb(self.port) };
+        }
+        loop {
+            interrupts::disable();
+            if let Some(byte) = self.buffers.rx.pop() {
+                update_rts(self.port, self.buffers);
+                interrupts::enable();
+                return byte;
+            }
+            interrupts::enable_and_halt();
+        }
     }
 
     /// Reads a char.
     #[inline(always)]
+    #[allow(dead_code)]
     pub fn read_char(&self) -> char {
         self.read_byte() as char
     }
@@ -0,0 +1,90 @@
+use core::ptr;
+use core::mem::size_of;
+
+/// The IST index of the double fault stack.
+pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+
+/// The IST index of the non-maskable interrupt stack.
+pub const NMI_IST_INDEX: usize = 1;
+
+/// The size of an interrupt stack.
+const STACK_SIZE: usize = 4096 * 4;
+
+/// The double fault stack.
+static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+/// The non-maskable interrupt stack.
+static mut NMI_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+/// The task state segment.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The `TaskStateSegment` type.
+///
+/// Represents a 64-bit task state segment.
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    /// Reserved.
+    reserved_1: u32,
+
+    /// The stack pointers for privilege levels 0 to 2.
+    pub privilege_stack_table: [u64; 3],
+
+    /// Reserved.
+    reserved_2: u64,
+
+    /// The interrupt stack table.
+    pub interrupt_stack_table: [u64; 7],
+
+    /// Reserved.
+    reserved_3: u64,
+
+    /// Reserved.
+    reserved_4: u16,
+
+    /// The offset of the I/O permission bitmap.
+    pub iomap_base: u16,
+}
+
+/// The `TaskStateSegment` implementation.
+impl TaskStateSegment {
+    /// Constructs a new empty `TaskStateSegment`.
+    pub const fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+/// The `Default` implementation for `TaskStateSegment`.
+impl Default for TaskStateSegment {
+    fn default() -> TaskStateSegment {
+        TaskStateSegment::new()
+    }
+}
+
+/// Initializes the task state segment.
+///
+/// The returned segment still has to be added to the
+/// global descriptor table and loaded into the task register.
+pub fn init() -> &'static TaskStateSegment {
+    unsafe {
+        // Point the IST entries to the top of their stacks
+        let tss = &mut *ptr::addr_of_mut!(TSS);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
+            stack_top(&*ptr::addr_of!(DOUBLE_FAULT_STACK));
+        tss.interrupt_stack_table[NMI_IST_INDEX] = stack_top(&*ptr::addr_of!(NMI_STACK));
+        tss
+    }
+}
+
+/// Gets the 16-byte aligned top address of a stack.
+fn stack_top(stack: &[u8; STACK_SIZE]) -> u64 {
+    (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xF
+}
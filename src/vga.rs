@@ -1,25 +1,26 @@
-use core::ptr::Unique;
+use core::ptr::NonNull;
 use spin::Mutex;
+use memory::KERNEL_OFFSET;
 
-macro_rules! println {
-    ($fmt:expr) => (print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+macro_rules! println {
+    ($fmt:expr) => (print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
-macro_rules! print {
-    ($($arg:tt)*) => ({
-            use core::fmt::Write;
-            let mut writer = $crate::vga::Console.lock();
-            writer.write_fmt(format_args!($($arg)*)).unwrap();
-    });
+macro_rules! print {
+    ($($arg:tt)*) => ({
+            use core::fmt::Write;
+            let mut writer = $crate::vga::CONSOLE.lock();
+            writer.write_fmt(format_args!($($arg)*)).unwrap();
+    });
 }
 
 /// A static VGA buffer writer.
-pub static Console: Mutex<Writer> = Mutex::new(Writer {
+pub static CONSOLE: Mutex<Writer> = Mutex::new(Writer {
     col: 0,
     row: 0,
     color: Color::new(HalfColor::White, HalfColor::Black),
-    buffer: unsafe { Unique::new(0xB8000 as *mut _) },
+    buffer: unsafe { NonNull::new_unchecked((KERNEL_OFFSET + 0xB8000) as *mut _) },
 });
 
 /// The buffer width.
@@ -56,7 +57,10 @@ pub enum HalfColor {
 }
 
 /// The `Color` type.
+///
+/// Only read by the VGA hardware.
 #[derive(Copy, Clone)]
+#[allow(dead_code)]
 pub struct Color(u8);
 
 /// The `Color` implementation.
@@ -96,9 +100,14 @@ pub struct Writer {
     /// The color.
     color: Color,
     /// The buffer.
-    buffer: Unique<Buffer>,
+    buffer: NonNull<Buffer>,
 }
 
+/// The `Send` implementation for `Writer`.
+///
+/// The VGA buffer is only written through `CONSOLE`.
+unsafe impl Send for Writer {}
+
 /// The `::core::fmt::Write` implementation for `Writer`.
 impl ::core::fmt::Write for Writer {
     #[inline(always)]
@@ -115,49 +124,42 @@ impl Writer {
     /// Writes a byte.
     #[inline(always)]
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            b'\r' => self.col = 0,
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
             b'\t' => {
                 for _ in 0..(TAB_WIDTH - (self.col % TAB_WIDTH)) {
                     self.write_byte(b' ');
                 }
-            }
+            }
             0x08 => {
                 // Backspace
                 let blank = Character {
                     char_code: b' ',
                     color: self.color,
                 };
-                if self.col == 0 && self.row == 0 {
-                    return;
-                } else if self.col == 0 {
-                    self.buffer().chars[self.row][self.col] = blank;
+                if self.col > 0 {
+                    let (row, col) = (self.row, self.col);
+                    self.buffer().chars[row][col] = blank;
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    let (row, col) = (self.row, self.col);
+                    self.buffer().chars[row][col] = blank;
                     self.row -= 1;
                     self.col = BUFFER_WIDTH - 1;
-                } else {
-                    self.buffer().chars[self.row][self.col] = blank;
-                    self.col -= 1;
                 }
-            }
+            }
             _ => {
                 if self.col >= BUFFER_WIDTH {
                     self.new_line();
                 }
-                self.buffer().chars[self.row][self.col] = Character {
+                let (row, col) = (self.row, self.col);
+                self.buffer().chars[row][col] = Character {
                     char_code: byte,
                     color: self.color,
                 };
                 self.col += 1;
-            }
-        }
-    }
-
-    /// Writes a string.
-    #[inline(always)]
-    pub fn write_str(&mut self, string: &str) {
-        for byte in string.bytes() {
-            self.write_byte(byte)
+            }
         }
     }
 
@@ -182,9 +184,10 @@ impl Writer {
 
     /// Sets the cursor to the specified position.
     #[inline(always)]
+    #[cfg_attr(test, allow(dead_code))]
     pub fn set_cursor(&mut self, x: usize, y: usize) {
         fn clamp(value: usize, min: usize, max: usize) -> usize {
-            return if value < min {
+            if value < min {
                 min
             } else {
                 if value > max {
@@ -192,7 +195,7 @@ impl Writer {
                 } else {
                     value
                 }
-            };
+            }
         }
         self.col = clamp(x, 0, BUFFER_WIDTH);
         self.row = clamp(y, 0, BUFFER_HEIGHT);
@@ -233,6 +236,6 @@ impl Writer {
     /// Gets a mutable reference to the buffer.
     #[inline(always)]
     fn buffer(&mut self) -> &mut Buffer {
-        unsafe { self.buffer.get_mut() }
+        unsafe { self.buffer.as_mut() }
     }
 }